@@ -0,0 +1,83 @@
+//! Simulates thousands of connections hitting the session storage at once,
+//! every one of them inserting its session (the CONNECT) and then looking it
+//! up for each of its requests. The sharded storage is compared against one
+//! with a single shard, which behaves like the old global lock.
+//!
+//! Run with `cargo bench --bench storage`
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[allow(dead_code)]
+#[path = "../src/storage/sharded.rs"]
+mod sharded;
+
+use sharded::Storage;
+
+const CONNECTIONS: usize = 5_000;
+const LOOKUPS_PER_CONNECTION: usize = 1_000;
+const SESSION_TIME: Duration = Duration::from_secs(10 * 60);
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .build()
+        .expect("Failed to build the runtime");
+
+    let keys: Arc<Vec<String>> = Arc::new((0..CONNECTIONS).map(|i| format!("conn-{i}")).collect());
+
+    for (name, storage) in [
+        ("single shard", Storage::with_shards(1, None)),
+        ("sharded", Storage::new()),
+    ] {
+        let elapsed = runtime.block_on(run(Arc::new(storage), keys.clone()));
+        let lookups = CONNECTIONS * LOOKUPS_PER_CONNECTION;
+
+        println!(
+            "{name:>12}: {lookups} lookups over {CONNECTIONS} connections in {elapsed:?} ({:.0} lookups/s)",
+            lookups as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+async fn run(storage: Arc<Storage<String, String>>, keys: Arc<Vec<String>>) -> Duration {
+    let start = Instant::now();
+
+    let tasks: Vec<_> = (0..CONNECTIONS)
+        .map(|conn| {
+            let storage = storage.clone();
+            let keys = keys.clone();
+
+            tokio::spawn(async move {
+                storage.set_with_duration(
+                    keys[conn].clone(),
+                    format!("session-{conn}"),
+                    SESSION_TIME,
+                );
+
+                for lookup in 0..LOOKUPS_PER_CONNECTION {
+                    // Mostly its own session, with the odd lookup of another connection
+                    let key = if lookup % 10 == 0 {
+                        &keys[(conn + lookup) % CONNECTIONS]
+                    } else {
+                        &keys[conn]
+                    };
+
+                    std::hint::black_box(storage.get(key));
+
+                    // Yield now and then, like a connection waiting on the network would
+                    if lookup % 64 == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        task.await.expect("A connection task panicked");
+    }
+
+    start.elapsed()
+}
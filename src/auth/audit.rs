@@ -0,0 +1,21 @@
+//! Structured audit events for authentication attempts. They are logged under
+//! the `hud::audit` target so they can be filtered and shipped separately
+
+use std::net::SocketAddr;
+
+use log::{debug, warn};
+
+const AUDIT_TARGET: &str = "hud::audit";
+
+pub fn auth_success(addr: SocketAddr, customer: &str) {
+    debug!(target: AUDIT_TARGET, "event=auth outcome=success customer={customer:?} addr={addr}");
+}
+
+pub fn auth_failure(addr: SocketAddr, customer: Option<&str>, reason: &str) {
+    let customer = customer.unwrap_or("-");
+
+    warn!(
+        target: AUDIT_TARGET,
+        "event=auth outcome=failure customer={customer:?} addr={addr} reason={reason:?}"
+    );
+}
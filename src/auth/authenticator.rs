@@ -0,0 +1,373 @@
+//! Credential backends used to verify a parsed [`Session`]
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use error_stack::{bail, IntoReport, Report, Result, ResultExt};
+use hudsucker::async_trait::async_trait;
+use log::{info, warn};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+use super::{password::StoredPassword, LockoutPolicy, Session};
+use crate::{
+    browser::Browser,
+    config::{vars, ConfigError},
+    timeouts::TimeoutOverrides,
+};
+
+/// Selects the backend to use, one of `file`, `env` or `allow-all`
+const AUTH_BACKEND: &str = "HUD_AUTH";
+/// Path to the users file when using the `file` backend, replacing the users
+/// of the configuration file
+const USERS_FILE: &str = "HUD_USERS_FILE";
+/// Credentials for the `env` backend, the hash takes precedence over the
+/// plaintext password
+const AUTH_USER: &str = "HUD_USER";
+const AUTH_PASSWORD_HASH: &str = "HUD_PASSWORD_HASH";
+const AUTH_PASSWORD: &str = "HUD_PASSWORD";
+
+#[derive(Debug, Error)]
+pub enum AuthenticateError {
+    #[error("The customer does not exist")]
+    UnknownCustomer,
+    #[error("The password does not match")]
+    WrongPassword,
+}
+
+/// Per-customer settings returned by a backend on a successful login
+#[derive(Debug, Clone, Default)]
+pub struct CustomerProfile {
+    /// Browser profile used when the username doesn't pick one
+    pub browser: Option<Browser>,
+    pub timeouts: TimeoutOverrides,
+    /// Whether sticky sessions may switch exits when their upstream fails
+    pub failover: bool,
+}
+
+/// Verifies the credentials contained in a [`Session`]
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(
+        &self,
+        session: &Session,
+        client_addr: SocketAddr,
+    ) -> Result<CustomerProfile, AuthenticateError>;
+}
+
+/// Lets every request through, meant for local development only
+pub struct AllowAllAuthenticator;
+
+#[async_trait]
+impl Authenticator for AllowAllAuthenticator {
+    async fn authenticate(
+        &self,
+        _session: &Session,
+        _addr: SocketAddr,
+    ) -> Result<CustomerProfile, AuthenticateError> {
+        Ok(CustomerProfile::default())
+    }
+}
+
+/// A single user provided through the configuration or the environment
+pub struct EnvAuthenticator {
+    customer: String,
+    password: StoredPassword,
+}
+
+impl EnvAuthenticator {
+    pub fn new(customer: String, password: StoredPassword) -> Self {
+        Self { customer, password }
+    }
+}
+
+#[async_trait]
+impl Authenticator for EnvAuthenticator {
+    async fn authenticate(
+        &self,
+        session: &Session,
+        _addr: SocketAddr,
+    ) -> Result<CustomerProfile, AuthenticateError> {
+        let known: bool = session
+            .customer()
+            .as_bytes()
+            .ct_eq(self.customer.as_bytes())
+            .into();
+        // Verified for unknown customers as well so both are rejected in the
+        // same time
+        let matches = self.password.verify(session.password()).await;
+
+        if !known {
+            bail!(Report::new(AuthenticateError::UnknownCustomer));
+        }
+
+        if !matches {
+            bail!(Report::new(AuthenticateError::WrongPassword));
+        }
+
+        Ok(CustomerProfile::default())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UsersFile {
+    users: Vec<UserEntry>,
+}
+
+/// A user as written in the users file or in the `[[auth.users]]` section of
+/// the configuration
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserEntry {
+    customer: String,
+    password_hash: String,
+    browser: Option<Browser>,
+    #[serde(default)]
+    timeouts: TimeoutOverrides,
+    #[serde(default)]
+    failover: bool,
+}
+
+struct User {
+    password: StoredPassword,
+    profile: CustomerProfile,
+}
+
+/// A static list of users loaded from a TOML or JSON file, or listed in the
+/// configuration. Passwords must be stored as argon2id or bcrypt hashes
+pub struct UsersFileAuthenticator {
+    users: HashMap<String, User>,
+    /// Verified against for unknown customers, hashed with the algorithm of
+    /// the users so both are rejected in the same time
+    dummy: Option<StoredPassword>,
+}
+
+#[derive(Debug, Error)]
+#[error("Could not load the users file")]
+pub struct LoadUsersError;
+
+impl UsersFileAuthenticator {
+    /// Loads the users from the given path, the format is picked based on the
+    /// file extension
+    pub fn from_path(path: &Path) -> Result<Self, LoadUsersError> {
+        let contents = fs::read_to_string(path)
+            .into_report()
+            .attach_printable_lazy(|| format!("Path: {}", path.display()))
+            .change_context(LoadUsersError)?;
+
+        let parsed: UsersFile = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents)
+                .into_report()
+                .change_context(LoadUsersError)?,
+            Some("json") => serde_json::from_str(&contents)
+                .into_report()
+                .change_context(LoadUsersError)?,
+            _ => bail!(Report::new(LoadUsersError)
+                .attach_printable(format!("Unsupported file type {}", path.display()))),
+        };
+
+        Self::from_entries(parsed.users)
+            .attach_printable_lazy(|| format!("Path: {}", path.display()))
+    }
+
+    pub fn from_entries(entries: Vec<UserEntry>) -> Result<Self, LoadUsersError> {
+        let mut users = HashMap::with_capacity(entries.len());
+
+        for entry in entries {
+            let password = StoredPassword::from_hash(&entry.password_hash)
+                .attach_printable_lazy(|| format!("User: {}", entry.customer))
+                .change_context(LoadUsersError)?;
+
+            let user = User {
+                password,
+                profile: CustomerProfile {
+                    browser: entry.browser,
+                    timeouts: entry.timeouts,
+                    failover: entry.failover,
+                },
+            };
+
+            if users.insert(entry.customer.clone(), user).is_some() {
+                warn!("Duplicate user \"{}\" in the users file", entry.customer);
+            }
+        }
+
+        let dummy = users
+            .values()
+            .find_map(|user| user.password.algorithm())
+            .map(StoredPassword::dummy)
+            .transpose()
+            .change_context(LoadUsersError)?;
+
+        Ok(Self { users, dummy })
+    }
+}
+
+#[async_trait]
+impl Authenticator for UsersFileAuthenticator {
+    async fn authenticate(
+        &self,
+        session: &Session,
+        _addr: SocketAddr,
+    ) -> Result<CustomerProfile, AuthenticateError> {
+        match self.users.get(session.customer()) {
+            Some(user) if user.password.verify(session.password()).await => {
+                Ok(user.profile.clone())
+            }
+            Some(_) => Err(Report::new(AuthenticateError::WrongPassword)),
+            None => {
+                if let Some(dummy) = &self.dummy {
+                    dummy.verify(session.password()).await;
+                }
+
+                Err(Report::new(AuthenticateError::UnknownCustomer))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Could not set up the authentication backend")]
+pub struct AuthenticatorSetupError;
+
+/// The backend verifying the credentials of the customers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthBackend {
+    /// A single user, see [`EnvAuthenticator`]
+    #[default]
+    Env,
+    /// A list of users, see [`UsersFileAuthenticator`]
+    File,
+    AllowAll,
+}
+
+impl AuthBackend {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "env" => Some(Self::Env),
+            "file" => Some(Self::File),
+            "allow-all" => Some(Self::AllowAll),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Env => "env",
+            Self::File => "file",
+            Self::AllowAll => "allow-all",
+        }
+    }
+}
+
+/// The `[auth]` section of the configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub backend: AuthBackend,
+    /// Users of the `file` backend, mutually exclusive with `users`
+    pub users_file: Option<PathBuf>,
+    pub users: Vec<UserEntry>,
+    /// The customer of the `env` backend
+    pub user: Option<String>,
+    pub password_hash: Option<String>,
+    /// Plaintext password of the `env` backend, only accepted from the
+    /// environment
+    #[serde(skip)]
+    pub password: Option<String>,
+    pub lockout: LockoutPolicy,
+}
+
+impl AuthConfig {
+    /// Overrides the configuration with `HUD_AUTH`, `HUD_USERS_FILE`,
+    /// `HUD_USER`, `HUD_PASSWORD_HASH`, `HUD_PASSWORD` and the lockout
+    /// variables
+    pub fn with_env(self) -> Result<Self, ConfigError> {
+        let users_file = vars::parse(USERS_FILE)?;
+        let password_hash = vars::string(AUTH_PASSWORD_HASH);
+        let password = vars::string(AUTH_PASSWORD);
+
+        Ok(Self {
+            backend: vars::parse_with(AUTH_BACKEND, AuthBackend::from_name)?
+                .unwrap_or(self.backend),
+            users: match users_file {
+                Some(_) => Vec::new(),
+                None => self.users,
+            },
+            users_file: users_file.or(self.users_file),
+            user: vars::string(AUTH_USER).or(self.user),
+            // A password from the environment replaces the configured hash
+            password_hash: match password {
+                Some(_) => password_hash,
+                None => password_hash.or(self.password_hash),
+            },
+            password,
+            lockout: self.lockout.with_env()?,
+        })
+    }
+}
+
+/// Builds the authentication backend picked in the configuration
+pub fn authenticator_from_config(
+    config: &AuthConfig,
+) -> Result<Arc<dyn Authenticator>, AuthenticatorSetupError> {
+    let authenticator: Arc<dyn Authenticator> = match config.backend {
+        AuthBackend::File => {
+            let users = match &config.users_file {
+                Some(_) if !config.users.is_empty() => bail!(Report::new(AuthenticatorSetupError)
+                    .attach_printable("Users are listed inline and in a users file, pick one")),
+                Some(path) => UsersFileAuthenticator::from_path(path),
+                None if config.users.is_empty() => bail!(Report::new(AuthenticatorSetupError)
+                    .attach_printable(format!(
+                        "The file backend needs `users_file`, {USERS_FILE} or `[[auth.users]]`"
+                    ))),
+                None => UsersFileAuthenticator::from_entries(config.users.clone()),
+            };
+
+            Arc::new(users.change_context(AuthenticatorSetupError)?)
+        }
+        AuthBackend::Env => {
+            let customer = config.user.clone().ok_or_else(|| {
+                Report::new(AuthenticatorSetupError)
+                    .attach_printable(format!("The env backend needs `user` or {AUTH_USER}"))
+            })?;
+
+            let password = match (&config.password_hash, &config.password) {
+                (Some(hash), _) => {
+                    StoredPassword::from_hash(hash).change_context(AuthenticatorSetupError)?
+                }
+                (None, Some(plain)) => {
+                    warn!("{AUTH_PASSWORD} is stored in plaintext, prefer {AUTH_PASSWORD_HASH}");
+
+                    StoredPassword::Plain(plain.clone())
+                }
+                (None, None) => bail!(Report::new(AuthenticatorSetupError).attach_printable(
+                    format!(
+                        "The env backend needs `password_hash`, {AUTH_PASSWORD_HASH} or \
+                         {AUTH_PASSWORD}"
+                    )
+                )),
+            };
+
+            Arc::new(EnvAuthenticator::new(customer, password))
+        }
+        AuthBackend::AllowAll => {
+            warn!("Authentication is disabled, every request will be let through");
+
+            Arc::new(AllowAllAuthenticator)
+        }
+    };
+
+    info!(
+        "Using the \"{}\" authentication backend",
+        config.backend.name()
+    );
+
+    Ok(authenticator)
+}
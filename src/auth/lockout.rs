@@ -0,0 +1,205 @@
+//! Brute-force protection for the CONNECT authentication. Failed attempts are
+//! tracked per client IP and per customer, and once too many of them pile up
+//! the key is locked for an exponentially growing amount of time
+
+use std::{
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use error_stack::Result;
+use serde::Deserialize;
+
+use crate::{
+    config::{duration_str, vars, ConfigError},
+    storage::{Storage, StorageMetrics, Sweep},
+};
+
+const MAX_FAILURES: &str = "HUD_LOCKOUT_MAX_FAILURES";
+const BASE_BAN_SECS: &str = "HUD_LOCKOUT_BAN_SECS";
+const MAX_BAN_SECS: &str = "HUD_LOCKOUT_MAX_BAN_SECS";
+const FAILURE_WINDOW_SECS: &str = "HUD_LOCKOUT_WINDOW_SECS";
+const MAX_TRACKED: &str = "HUD_LOCKOUT_MAX_TRACKED";
+
+/// Thresholds used by the [`FailureTracker`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutPolicy {
+    /// Failed attempts allowed before a key gets locked
+    pub max_failures: u32,
+    /// Length of the first lock, doubled with every consecutive one
+    #[serde(with = "duration_str")]
+    pub base_ban: Duration,
+    /// Upper bound for the lock length
+    #[serde(with = "duration_str")]
+    pub max_ban: Duration,
+    /// How long failures (and past locks) are remembered for
+    #[serde(with = "duration_str")]
+    pub failure_window: Duration,
+    /// Upper bound for the IPs and for the customers tracked at once, the
+    /// customer is picked by the client so it must not grow unbounded
+    pub max_tracked: usize,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            base_ban: Duration::from_secs(30),
+            max_ban: Duration::from_secs(60 * 60),
+            failure_window: Duration::from_secs(10 * 60),
+            max_tracked: 10_000,
+        }
+    }
+}
+
+impl LockoutPolicy {
+    /// Overrides the policy with any of the `HUD_LOCKOUT_*` environment
+    /// variables
+    pub fn with_env(self) -> Result<Self, ConfigError> {
+        Ok(Self {
+            max_failures: vars::parse(MAX_FAILURES)?.unwrap_or(self.max_failures),
+            base_ban: vars::secs(BASE_BAN_SECS)?.unwrap_or(self.base_ban),
+            max_ban: vars::secs(MAX_BAN_SECS)?.unwrap_or(self.max_ban),
+            failure_window: vars::secs(FAILURE_WINDOW_SECS)?.unwrap_or(self.failure_window),
+            max_tracked: vars::parse(MAX_TRACKED)?.unwrap_or(self.max_tracked),
+        })
+    }
+
+    fn ban_duration(&self, bans: u32) -> Duration {
+        let factor = 2u32.saturating_pow(bans.saturating_sub(1));
+
+        self.base_ban.saturating_mul(factor).min(self.max_ban)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct FailureRecord {
+    failures: u32,
+    bans: u32,
+    locked_until: Option<Instant>,
+}
+
+impl FailureRecord {
+    fn remaining_lock(&self) -> Option<Duration> {
+        self.locked_until
+            .and_then(|until| until.checked_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+/// Keeps track of failed authentication attempts
+pub struct FailureTracker {
+    policy: LockoutPolicy,
+    by_ip: Storage<IpAddr, FailureRecord>,
+    by_customer: Storage<String, FailureRecord>,
+}
+
+impl FailureTracker {
+    pub fn new(policy: LockoutPolicy) -> Self {
+        Self {
+            by_ip: Storage::with_capacity(policy.max_tracked),
+            by_customer: Storage::with_capacity(policy.max_tracked),
+            policy,
+        }
+    }
+
+    /// Get the remaining lock time for the given IP, if any
+    pub fn ip_locked(&self, ip: IpAddr) -> Option<Duration> {
+        remaining_lock(&self.by_ip, &ip)
+    }
+
+    /// Get the remaining lock time for the given customer, if any
+    pub fn customer_locked(&self, customer: &str) -> Option<Duration> {
+        remaining_lock(&self.by_customer, &customer.to_string())
+    }
+
+    /// Record a failed attempt, the customer is only known if the credentials
+    /// could be parsed
+    pub fn record_failure(&self, ip: IpAddr, customer: Option<&str>) {
+        record_failure(&self.by_ip, ip, &self.policy);
+
+        if let Some(customer) = customer {
+            record_failure(&self.by_customer, customer.to_string(), &self.policy);
+        }
+    }
+
+    /// Forget the failures of a customer after a successful login. The IP
+    /// record is left to expire, an attacker holding one valid account could
+    /// otherwise reset it between guesses at other customers
+    pub fn record_success(&self, customer: &str) {
+        self.by_customer.remove(&customer.to_string());
+    }
+}
+
+impl Sweep for FailureTracker {
+    fn sweep(&self) -> usize {
+        self.by_ip.sweep() + self.by_customer.sweep()
+    }
+
+    fn metrics(&self) -> StorageMetrics {
+        let (by_ip, by_customer) = (self.by_ip.metrics(), self.by_customer.metrics());
+
+        StorageMetrics {
+            entries: by_ip.entries + by_customer.entries,
+            evicted: by_ip.evicted + by_customer.evicted,
+        }
+    }
+}
+
+fn remaining_lock<K: Hash + Eq + Clone>(
+    storage: &Storage<K, FailureRecord>,
+    key: &K,
+) -> Option<Duration> {
+    storage.get(key)?.remaining_lock()
+}
+
+fn record_failure<K: Hash + Eq + Clone>(
+    storage: &Storage<K, FailureRecord>,
+    key: K,
+    policy: &LockoutPolicy,
+) {
+    storage.update_with(key, |record| {
+        let mut record = record.unwrap_or_default();
+        record.failures += 1;
+
+        if record.failures >= policy.max_failures {
+            record.failures = 0;
+            record.bans += 1;
+            record.locked_until = Some(Instant::now() + policy.ban_duration(record.bans));
+        }
+
+        // Keep the record around for the whole lock plus the failure window, so
+        // that consecutive locks keep growing
+        let ttl = record.remaining_lock().unwrap_or_default() + policy.failure_window;
+
+        (record, ttl)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn success_keeps_the_ip_failures() {
+        let tracker = FailureTracker::new(LockoutPolicy {
+            max_failures: 2,
+            ..LockoutPolicy::default()
+        });
+        let ip = IpAddr::from([127, 0, 0, 1]);
+
+        tracker.record_failure(ip, Some("victim"));
+        tracker.record_success("attacker");
+        tracker.record_failure(ip, Some("victim"));
+
+        assert!(tracker.ip_locked(ip).is_some());
+        assert!(tracker.customer_locked("victim").is_some());
+        assert!(tracker.customer_locked("attacker").is_none());
+
+        tracker.record_success("victim");
+        assert!(tracker.customer_locked("victim").is_none());
+        assert!(tracker.ip_locked(ip).is_some());
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use error_stack::{bail, IntoReport, Report, Result, ResultExt};
+use hudsucker::{
+    hyper::{Body, Request},
+    HttpContext,
+};
+use thiserror::Error;
+
+mod audit;
+mod authenticator;
+mod lockout;
+mod params;
+mod password;
+mod secret;
+mod session;
+
+use authenticator::AuthenticateError;
+pub use authenticator::{authenticator_from_config, AuthConfig, Authenticator};
+pub use lockout::{FailureTracker, LockoutPolicy};
+pub use params::{is_country_code, parse_duration};
+pub use password::{hash_password, HashAlgorithm};
+pub use secret::{DigestKey, DIGEST_KEY_LEN};
+pub use session::{Session, SessionDefaults, StoredSession};
+
+const BASIC_AUTH_PREFIX: &str = "Basic ";
+
+#[derive(Debug, Error)]
+pub enum CreateSessionError {
+    #[error("Could not extract valid data from the Proxy-Authorization header")]
+    MalformedHeader,
+    #[error("Failed login attempt for user \"{customer}\" ({addr})")]
+    Unauthorized { addr: String, customer: String },
+    #[error("No authorization header was provided")]
+    NoAuthHeader,
+    #[error("Too many failed login attempts, locked for another {retry_after:?}")]
+    LockedOut {
+        customer: Option<String>,
+        retry_after: Duration,
+    },
+}
+
+/// Creates a new [Session] based on the provided authorization information and
+/// checks its credentials against the given [Authenticator]. Every attempt is
+/// recorded as an audit event, and failures count towards a lockout in the
+/// [FailureTracker]
+pub async fn handle_auth(
+    ctx: &HttpContext,
+    req: &Request<Body>,
+    authenticator: &dyn Authenticator,
+    failure_tracker: &FailureTracker,
+    defaults: &SessionDefaults,
+) -> Result<Session, CreateSessionError> {
+    let ip = ctx.client_addr.ip();
+    let result = try_auth(ctx, req, authenticator, failure_tracker, defaults).await;
+
+    match &result {
+        Ok(session) => {
+            failure_tracker.record_success(session.customer());
+
+            audit::auth_success(ctx.client_addr, session.customer());
+        }
+        Err(report) => {
+            let (customer, reason) = match report.current_context() {
+                CreateSessionError::Unauthorized { customer, .. } => {
+                    failure_tracker.record_failure(ip, Some(customer.as_str()));
+
+                    (
+                        Some(customer.as_str()),
+                        report
+                            .downcast_ref::<AuthenticateError>()
+                            .map_or_else(|| "Invalid credentials".to_string(), ToString::to_string),
+                    )
+                }
+                CreateSessionError::MalformedHeader => {
+                    failure_tracker.record_failure(ip, None);
+
+                    (None, report.current_context().to_string())
+                }
+                CreateSessionError::LockedOut { customer, .. } => {
+                    (customer.as_deref(), report.current_context().to_string())
+                }
+                other => (None, other.to_string()),
+            };
+
+            audit::auth_failure(ctx.client_addr, customer, &reason);
+        }
+    }
+
+    result
+}
+
+/// Creates a [Session] without checking any credentials, for listeners that
+/// don't require authentication. The session always belongs to `customer`,
+/// only the other username parameters are honored if a Proxy-Authorization
+/// header is sent
+pub fn open_session(
+    ctx: &HttpContext,
+    req: &Request<Body>,
+    customer: &str,
+    defaults: &SessionDefaults,
+) -> Result<Session, CreateSessionError> {
+    let proxy_auth = req
+        .headers()
+        .get(hudsucker::hyper::header::PROXY_AUTHORIZATION);
+
+    let auth_header_str = match proxy_auth {
+        Some(auth) => {
+            let auth_header_str = auth
+                .to_str()
+                .into_report()
+                .change_context(CreateSessionError::MalformedHeader)?;
+
+            if !auth_header_str.starts_with(BASIC_AUTH_PREFIX) {
+                bail!(Report::new(CreateSessionError::MalformedHeader)
+                    .attach_printable("Unsupported authorization type".to_string()));
+            }
+
+            Some(auth_header_str)
+        }
+        None => None,
+    };
+
+    Session::anonymous(ctx, customer, auth_header_str, defaults)
+        .change_context(CreateSessionError::MalformedHeader)
+}
+
+async fn try_auth(
+    ctx: &HttpContext,
+    req: &Request<Body>,
+    authenticator: &dyn Authenticator,
+    failure_tracker: &FailureTracker,
+    defaults: &SessionDefaults,
+) -> Result<Session, CreateSessionError> {
+    if let Some(retry_after) = failure_tracker.ip_locked(ctx.client_addr.ip()) {
+        bail!(Report::new(CreateSessionError::LockedOut {
+            customer: None,
+            retry_after
+        }));
+    }
+
+    let proxy_auth = req
+        .headers()
+        .get(hudsucker::hyper::header::PROXY_AUTHORIZATION);
+
+    match proxy_auth {
+        Some(auth) => {
+            let auth_header_str = auth
+                .to_str()
+                .into_report()
+                .change_context(CreateSessionError::MalformedHeader)?;
+
+            if auth_header_str.starts_with(BASIC_AUTH_PREFIX) {
+                let session = Session::new(ctx, auth_header_str, defaults)
+                    .change_context(CreateSessionError::MalformedHeader)?;
+
+                if let Some(retry_after) = failure_tracker.customer_locked(session.customer()) {
+                    bail!(Report::new(CreateSessionError::LockedOut {
+                        customer: Some(session.customer().to_string()),
+                        retry_after
+                    }));
+                }
+
+                return match authenticator.authenticate(&session, ctx.client_addr).await {
+                    Ok(profile) => {
+                        let mut session = session;
+                        session.apply_profile(&profile);
+
+                        Ok(session)
+                    }
+                    Err(report) => Err(report.change_context(CreateSessionError::Unauthorized {
+                        addr: session.addr().to_string(),
+                        customer: session.customer().to_string(),
+                    })),
+                };
+            }
+
+            Err(Report::new(CreateSessionError::MalformedHeader)
+                .attach_printable("Unsupported authorization type".to_string()))
+        }
+        None => Err(Report::new(CreateSessionError::NoAuthHeader)),
+    }
+}
@@ -0,0 +1,416 @@
+//! Declarative schema for the parameters encoded in the proxy username
+//!
+//! A username like `customer-acme-country-us-session_time-30m` is split into
+//! key/value pairs, which are then matched against a list of [`ParamSpec`]s.
+//! Adding a new parameter only requires a new entry in the schema.
+
+use std::{collections::HashMap, time::Duration};
+
+use error_stack::{bail, Report, Result, ResultExt};
+use thiserror::Error;
+
+/// The type a parameter value is parsed into
+#[derive(Debug, Clone, Copy)]
+pub enum ParamKind {
+    /// Any string
+    Str,
+    /// A duration such as `30m`, plain numbers are treated as seconds
+    Duration,
+    /// `true`/`false`, also accepting `1`/`0`, `yes`/`no` and `on`/`off`
+    Bool,
+    /// One of the listed values, matched case-insensitively
+    Enum(&'static [&'static str]),
+    /// An ISO 3166-1 alpha-2 country code, stored uppercased
+    Country,
+}
+
+/// A parsed parameter value
+#[derive(Debug, Clone)]
+pub enum ParamValue {
+    Str(String),
+    Duration(Duration),
+    Bool(bool),
+    Enum(&'static str),
+    Country(String),
+}
+
+/// Describes a single username parameter
+#[derive(Debug, Clone, Copy)]
+pub struct ParamSpec {
+    name: &'static str,
+    aliases: &'static [&'static str],
+    kind: ParamKind,
+    required: bool,
+    default: Option<&'static str>,
+    max_len: usize,
+}
+
+const DEFAULT_MAX_LEN: usize = 32;
+
+impl ParamSpec {
+    /// A parameter that must always be present
+    pub const fn required(name: &'static str, kind: ParamKind) -> Self {
+        Self {
+            name,
+            aliases: &[],
+            kind,
+            required: true,
+            default: None,
+            max_len: DEFAULT_MAX_LEN,
+        }
+    }
+
+    /// A parameter that may be omitted
+    pub const fn optional(name: &'static str, kind: ParamKind) -> Self {
+        Self {
+            required: false,
+            ..Self::required(name, kind)
+        }
+    }
+
+    /// Other keys that may be used in place of the name
+    pub const fn aliases(self, aliases: &'static [&'static str]) -> Self {
+        Self { aliases, ..self }
+    }
+
+    /// The raw value used when the parameter is omitted
+    #[cfg(test)]
+    pub const fn default_value(self, default: &'static str) -> Self {
+        Self {
+            default: Some(default),
+            ..self
+        }
+    }
+
+    /// The maximum length of the raw value
+    #[cfg(test)]
+    pub const fn max_len(self, max_len: usize) -> Self {
+        Self { max_len, ..self }
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        self.name == key || self.aliases.contains(&key)
+    }
+
+    fn parse_value(&self, raw: &str) -> Result<ParamValue, ParseParamError> {
+        let invalid = || ParseParamError::Invalid {
+            name: self.name,
+            value: raw.to_string(),
+        };
+
+        check_param_length(raw, 1, self.max_len).change_context_lazy(invalid)?;
+
+        let value = match self.kind {
+            ParamKind::Str => ParamValue::Str(raw.to_string()),
+            ParamKind::Duration => ParamValue::Duration(parse_duration(raw).ok_or_else(|| {
+                Report::new(invalid()).attach_printable("Expected a duration such as 30m")
+            })?),
+            ParamKind::Bool => match raw.to_ascii_lowercase().as_str() {
+                "true" | "1" | "yes" | "on" => ParamValue::Bool(true),
+                "false" | "0" | "no" | "off" => ParamValue::Bool(false),
+                _ => bail!(Report::new(invalid()).attach_printable("Expected true or false")),
+            },
+            ParamKind::Enum(options) => ParamValue::Enum(
+                options
+                    .iter()
+                    .find(|option| option.eq_ignore_ascii_case(raw))
+                    .copied()
+                    .ok_or_else(|| {
+                        Report::new(invalid())
+                            .attach_printable(format!("Expected one of {}", options.join(", ")))
+                    })?,
+            ),
+            ParamKind::Country => {
+                let code = raw.to_ascii_uppercase();
+
+                if !is_country_code(&code) {
+                    bail!(Report::new(invalid())
+                        .attach_printable("Expected an ISO 3166-1 alpha-2 country code"));
+                }
+
+                ParamValue::Country(code)
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParseParamError {
+    #[error("Unknown parameter \"{0}\"")]
+    Unknown(String),
+    #[error("Parameter \"{0}\" was provided more than once")]
+    Duplicate(&'static str),
+    #[error("Missing required parameter \"{0}\"")]
+    Missing(&'static str),
+    #[error("Invalid value \"{value}\" for parameter \"{name}\"")]
+    Invalid { name: &'static str, value: String },
+}
+
+/// A list of [`ParamSpec`]s that a username is validated against
+pub struct ParamSchema(pub &'static [ParamSpec]);
+
+impl ParamSchema {
+    /// Parse the given key/value pairs, rejecting unknown and duplicated keys
+    pub fn parse<'a>(
+        &self,
+        pairs: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Params, ParseParamError> {
+        let mut values = HashMap::with_capacity(self.0.len());
+
+        for (key, raw) in pairs {
+            let spec = self
+                .0
+                .iter()
+                .find(|spec| spec.matches(key))
+                .ok_or_else(|| Report::new(ParseParamError::Unknown(key.to_string())))?;
+
+            if values.insert(spec.name, spec.parse_value(raw)?).is_some() {
+                bail!(Report::new(ParseParamError::Duplicate(spec.name)));
+            }
+        }
+
+        for spec in self.0 {
+            if values.contains_key(spec.name) {
+                continue;
+            }
+
+            if let Some(default) = spec.default {
+                values.insert(spec.name, spec.parse_value(default)?);
+            } else if spec.required {
+                bail!(Report::new(ParseParamError::Missing(spec.name)));
+            }
+        }
+
+        Ok(Params(values))
+    }
+}
+
+/// The validated parameters, keyed by their canonical name
+#[derive(Debug, Clone)]
+pub struct Params(HashMap<&'static str, ParamValue>);
+
+impl Params {
+    pub fn str(&self, name: &str) -> Option<&str> {
+        match self.0.get(name)? {
+            ParamValue::Str(s) | ParamValue::Country(s) => Some(s.as_str()),
+            ParamValue::Enum(s) => Some(*s),
+            _ => None,
+        }
+    }
+
+    pub fn duration(&self, name: &str) -> Option<Duration> {
+        match self.0.get(name)? {
+            ParamValue::Duration(d) => Some(*d),
+            _ => None,
+        }
+    }
+
+    pub fn bool(&self, name: &str) -> Option<bool> {
+        match self.0.get(name)? {
+            ParamValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+/// Parses durations such as `90`, `500ms`, `45s`, `30m`, `2h` or `1d`
+pub fn parse_duration(raw: &str) -> Option<Duration> {
+    let split = raw.find(|c: char| !c.is_ascii_digit()).unwrap_or(raw.len());
+    let (amount, unit) = raw.split_at(split);
+
+    let amount: u64 = amount.parse().ok()?;
+    let millis = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return None,
+    };
+
+    amount.checked_mul(millis).map(Duration::from_millis)
+}
+
+/// Every officially assigned ISO 3166-1 alpha-2 code
+const COUNTRY_CODES: &str = "\
+    AD AE AF AG AI AL AM AO AQ AR AS AT AU AW AX AZ BA BB BD BE BF BG BH BI BJ BL BM BN BO BQ BR \
+    BS BT BV BW BY BZ CA CC CD CF CG CH CI CK CL CM CN CO CR CU CV CW CX CY CZ DE DJ DK DM DO DZ \
+    EC EE EG EH ER ES ET FI FJ FK FM FO FR GA GB GD GE GF GG GH GI GL GM GN GP GQ GR GS GT GU GW \
+    GY HK HM HN HR HT HU ID IE IL IM IN IO IQ IR IS IT JE JM JO JP KE KG KH KI KM KN KP KR KW KY \
+    KZ LA LB LC LI LK LR LS LT LU LV LY MA MC MD ME MF MG MH MK ML MM MN MO MP MQ MR MS MT MU MV \
+    MW MX MY MZ NA NC NE NF NG NI NL NO NP NR NU NZ OM PA PE PF PG PH PK PL PM PN PR PS PT PW PY \
+    QA RE RO RS RU RW SA SB SC SD SE SG SH SI SJ SK SL SM SN SO SR SS ST SV SX SY SZ TC TD TF TG \
+    TH TJ TK TL TM TN TO TR TT TV TW TZ UA UG UM US UY UZ VA VC VE VG VI VN VU WF WS YE YT ZA ZM \
+    ZW";
+
+/// Checks if the (uppercase) code is an assigned ISO 3166-1 alpha-2 code
+pub fn is_country_code(code: &str) -> bool {
+    code.len() == 2 && COUNTRY_CODES.split_ascii_whitespace().any(|c| c == code)
+}
+
+#[derive(Debug, Error)]
+#[error(
+    "The string passed did not fit in the specified bounds. Expected {min}-{max}, found {found}"
+)]
+pub struct ParamLengthError {
+    min: usize,
+    max: usize,
+    found: usize,
+}
+
+pub fn check_param_length(s: &str, min: usize, max: usize) -> Result<(), ParamLengthError> {
+    let len = s.len();
+    if len < min || len > max {
+        bail!(Report::new(ParamLengthError {
+            min,
+            max,
+            found: len
+        }));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: ParamSchema = ParamSchema(&[
+        ParamSpec::required("customer", ParamKind::Str),
+        ParamSpec::optional("session_time", ParamKind::Duration).aliases(&["time"]),
+        ParamSpec::optional("sticky", ParamKind::Bool),
+        ParamSpec::optional("retry_after", ParamKind::Duration).default_value("3s"),
+        ParamSpec::optional("browser", ParamKind::Enum(&["chrome104"])),
+        ParamSpec::optional("country", ParamKind::Country).max_len(2),
+    ]);
+
+    fn parse(pairs: &[(&'static str, &'static str)]) -> Result<Params, ParseParamError> {
+        SCHEMA.parse(pairs.iter().copied())
+    }
+
+    #[test]
+    fn duration_units() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("45s"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(
+            parse_duration("1d"),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn duration_zero() {
+        assert_eq!(parse_duration("0"), Some(Duration::ZERO));
+        assert_eq!(parse_duration("0ms"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn duration_rejects_malformed() {
+        for raw in ["", "s", "-5", "1.5s", "5 m", "5M", "5w", "m5", "5ms5"] {
+            assert_eq!(parse_duration(raw), None, "{raw}");
+        }
+    }
+
+    #[test]
+    fn duration_overflow() {
+        assert_eq!(parse_duration("18446744073709551616"), None);
+        assert_eq!(parse_duration("18446744073709551615d"), None);
+        assert_eq!(parse_duration("18446744073709551615s"), None);
+        assert_eq!(
+            parse_duration("18446744073709551615ms"),
+            Some(Duration::from_millis(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn schema_parses_every_kind() {
+        let params = parse(&[
+            ("customer", "acme"),
+            ("time", "30m"),
+            ("sticky", "Yes"),
+            ("browser", "CHROME104"),
+            ("country", "us"),
+        ])
+        .unwrap();
+
+        assert_eq!(params.str("customer"), Some("acme"));
+        assert_eq!(
+            params.duration("session_time"),
+            Some(Duration::from_secs(30 * 60))
+        );
+        assert_eq!(params.bool("sticky"), Some(true));
+        assert_eq!(params.str("browser"), Some("chrome104"));
+        assert_eq!(params.str("country"), Some("US"));
+    }
+
+    #[test]
+    fn schema_applies_defaults() {
+        let params = parse(&[("customer", "acme")]).unwrap();
+
+        assert_eq!(params.duration("retry_after"), Some(Duration::from_secs(3)));
+        assert_eq!(params.duration("session_time"), None);
+        assert_eq!(params.bool("sticky"), None);
+    }
+
+    #[test]
+    fn schema_rejects_missing_required() {
+        let err = parse(&[("time", "30m")]).unwrap_err();
+
+        assert!(matches!(
+            err.current_context(),
+            ParseParamError::Missing("customer")
+        ));
+    }
+
+    #[test]
+    fn schema_rejects_unknown_and_duplicates() {
+        let err = parse(&[("customer", "acme"), ("zone", "1")]).unwrap_err();
+        assert!(matches!(err.current_context(), ParseParamError::Unknown(key) if key == "zone"));
+
+        // An alias counts as the parameter it stands for
+        let err =
+            parse(&[("customer", "acme"), ("session_time", "1m"), ("time", "2m")]).unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            ParseParamError::Duplicate("session_time")
+        ));
+    }
+
+    #[test]
+    fn schema_rejects_invalid_values() {
+        for pairs in [
+            [("customer", "acme"), ("time", "soon")],
+            [("customer", "acme"), ("sticky", "maybe")],
+            [("customer", "acme"), ("retry_after", "-1")],
+            [("customer", "acme"), ("browser", "netscape")],
+            [("customer", "acme"), ("country", "xx")],
+            [("customer", "acme"), ("country", "usa")],
+        ] {
+            let err = parse(&pairs).unwrap_err();
+
+            assert!(
+                matches!(err.current_context(), ParseParamError::Invalid { .. }),
+                "{pairs:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn schema_enforces_length() {
+        let long = "a".repeat(DEFAULT_MAX_LEN + 1);
+        let err = SCHEMA.parse([("customer", long.as_str())]).unwrap_err();
+
+        assert!(matches!(
+            err.current_context(),
+            ParseParamError::Invalid {
+                name: "customer",
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+//! Password hashing and verification for the credential backends
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use error_stack::{bail, IntoReport, Report, Result, ResultExt};
+use log::error;
+use rand_core::OsRng;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+const ARGON2ID_PREFIX: &str = "$argon2id$";
+const BCRYPT_PREFIXES: [&str; 3] = ["$2a$", "$2b$", "$2y$"];
+const DUMMY_PASSWORD: &str = "hudsucker-unknown-customer";
+
+/// A password as kept by a credential backend
+#[derive(Clone)]
+pub enum StoredPassword {
+    /// PHC formatted argon2id hash
+    Argon2(String),
+    /// Modular crypt formatted bcrypt hash
+    Bcrypt(String),
+    /// Plaintext password, only accepted from the environment
+    Plain(String),
+}
+
+#[derive(Debug, Error)]
+#[error("The stored password is not a supported hash")]
+pub struct UnsupportedHashError;
+
+impl StoredPassword {
+    /// Parses a hash string, rejecting anything that isn't argon2id or bcrypt
+    pub fn from_hash(hash: &str) -> Result<Self, UnsupportedHashError> {
+        if hash.starts_with(ARGON2ID_PREFIX) {
+            PasswordHash::new(hash).map_err(|err| {
+                Report::new(UnsupportedHashError).attach_printable(err.to_string())
+            })?;
+
+            return Ok(Self::Argon2(hash.to_string()));
+        }
+
+        if BCRYPT_PREFIXES
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            return Ok(Self::Bcrypt(hash.to_string()));
+        }
+
+        bail!(Report::new(UnsupportedHashError)
+            .attach_printable("Expected an argon2id ($argon2id$) or bcrypt ($2b$) hash"))
+    }
+
+    /// Hashes a password nobody knows, verified against for unknown customers so
+    /// they take as long to reject as a wrong password
+    pub fn dummy(algorithm: HashAlgorithm) -> Result<Self, HashPasswordError> {
+        let hash = hash_password(DUMMY_PASSWORD, algorithm)?;
+
+        Ok(match algorithm {
+            HashAlgorithm::Argon2id => Self::Argon2(hash),
+            HashAlgorithm::Bcrypt => Self::Bcrypt(hash),
+        })
+    }
+
+    /// The algorithm of the hash, `None` for plaintext passwords
+    pub fn algorithm(&self) -> Option<HashAlgorithm> {
+        match self {
+            Self::Argon2(_) => Some(HashAlgorithm::Argon2id),
+            Self::Bcrypt(_) => Some(HashAlgorithm::Bcrypt),
+            Self::Plain(_) => None,
+        }
+    }
+
+    /// Checks the attempted password in constant time. Hash verification is
+    /// moved off the async workers since it is deliberately slow
+    pub async fn verify(&self, attempt: &str) -> bool {
+        let stored = self.clone();
+        let attempt = attempt.to_string();
+
+        match tokio::task::spawn_blocking(move || stored.verify_blocking(&attempt)).await {
+            Ok(matches) => matches,
+            Err(err) => {
+                error!("Password verification task failed: {err}");
+                false
+            }
+        }
+    }
+
+    fn verify_blocking(&self, attempt: &str) -> bool {
+        match self {
+            Self::Argon2(hash) => match PasswordHash::new(hash) {
+                Ok(parsed) => Argon2::default()
+                    .verify_password(attempt.as_bytes(), &parsed)
+                    .is_ok(),
+                Err(_) => false,
+            },
+            Self::Bcrypt(hash) => bcrypt::verify(attempt, hash).unwrap_or(false),
+            Self::Plain(password) => password.as_bytes().ct_eq(attempt.as_bytes()).into(),
+        }
+    }
+}
+
+/// The algorithms available when hashing a new password
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum HashAlgorithm {
+    Argon2id,
+    Bcrypt,
+}
+
+#[derive(Debug, Error)]
+#[error("Could not hash the password")]
+pub struct HashPasswordError;
+
+/// Hashes a password so it can be stored in a users file
+pub fn hash_password(
+    password: &str,
+    algorithm: HashAlgorithm,
+) -> Result<String, HashPasswordError> {
+    match algorithm {
+        HashAlgorithm::Argon2id => {
+            let salt = SaltString::generate(&mut OsRng);
+
+            Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|err| Report::new(HashPasswordError).attach_printable(err.to_string()))
+        }
+        HashAlgorithm::Bcrypt => bcrypt::hash(password, bcrypt::DEFAULT_COST)
+            .into_report()
+            .change_context(HashPasswordError),
+    }
+}
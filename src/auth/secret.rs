@@ -0,0 +1,76 @@
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha1::Sha1;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Length of a [`DigestKey`] in bytes
+pub const DIGEST_KEY_LEN: usize = 32;
+
+/// Holds a value that must never end up in logs or error messages. Both the
+/// [`Debug`](fmt::Debug) and [`Display`](fmt::Display) implementations redact it
+#[derive(Clone)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// Access the actual value, only to be used where it is strictly needed
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({REDACTED})")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// Key of the digests that stand in for passwords wherever they would
+/// otherwise be kept around, e.g. on disk. The same key must be used across
+/// restarts for the digests to match
+#[derive(Clone)]
+pub struct DigestKey(Secret<[u8; DIGEST_KEY_LEN]>);
+
+impl DigestKey {
+    pub fn generate() -> Self {
+        let mut key = [0; DIGEST_KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+
+        Self(Secret::new(key))
+    }
+
+    pub fn from_bytes(key: [u8; DIGEST_KEY_LEN]) -> Self {
+        Self(Secret::new(key))
+    }
+
+    pub fn expose(&self) -> &[u8; DIGEST_KEY_LEN] {
+        self.0.expose()
+    }
+
+    /// A hex encoded HMAC of the value, which can't be reversed without the key
+    pub fn digest(&self, value: &str) -> String {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(self.0.expose()).expect("HMAC accepts keys of any length");
+        mac.update(value.as_bytes());
+
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+impl fmt::Debug for DigestKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DigestKey({REDACTED})")
+    }
+}
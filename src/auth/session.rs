@@ -0,0 +1,415 @@
+use std::{net::SocketAddr, time::Duration};
+
+use error_stack::{bail, IntoReport, Report, Result, ResultExt};
+use hudsucker::HttpContext;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{
+    authenticator::CustomerProfile,
+    params::{check_param_length, ParamKind, ParamSchema, ParamSpec, Params},
+    secret::{DigestKey, Secret},
+    BASIC_AUTH_PREFIX,
+};
+use crate::{
+    browser::Browser,
+    config::{duration_str, vars, ConfigError},
+    timeouts::{TimeoutOverrides, Timeouts},
+};
+
+const CUSTOMER: &str = "customer";
+const SESSION_ID: &str = "session_id";
+const COUNTRY: &str = "country";
+const SESSION_TIME: &str = "session_time";
+const BROWSER: &str = "browser";
+const TIMEOUT: &str = "timeout";
+const FAILOVER: &str = "failover";
+
+/// The parameters accepted in the username, in the form `key-value-key-value`.
+/// Only the customer is mandatory:
+/// - Without a session id every request gets a fresh (rotating) client
+/// - Without a country any exit may be used
+/// - Without a session time the server default applies
+/// - Without a browser the customer default, or else the server default applies
+/// - Without a timeout the total timeout of the customer, route or server applies
+/// - Without failover sticky sessions only fail over if the customer allows it
+const SESSION_PARAM_SPECS: &[ParamSpec] = &[
+    ParamSpec::required(CUSTOMER, ParamKind::Str),
+    ParamSpec::optional(SESSION_ID, ParamKind::Str).aliases(&["session", "sid"]),
+    ParamSpec::optional(COUNTRY, ParamKind::Country).aliases(&["cc"]),
+    ParamSpec::optional(SESSION_TIME, ParamKind::Duration).aliases(&["time"]),
+    ParamSpec::optional(BROWSER, ParamKind::Enum(Browser::NAMES)).aliases(&["profile"]),
+    ParamSpec::optional(TIMEOUT, ParamKind::Duration),
+    ParamSpec::optional(FAILOVER, ParamKind::Bool),
+];
+
+const SESSION_PARAMS: ParamSchema = ParamSchema(SESSION_PARAM_SPECS);
+
+/// The parameters honored by listeners without authentication, which leave out
+/// the customer as their sessions always belong to the listener
+const ANONYMOUS_PARAMS: ParamSchema = ParamSchema(match SESSION_PARAM_SPECS {
+    // The customer comes first
+    [_, rest @ ..] => rest,
+    [] => &[],
+});
+
+const MAX_PASSWORD_LEN: usize = 64;
+
+const DEFAULT_SESSION_TIME: &str = "HUD_DEFAULT_SESSION_TIME";
+const DEFAULT_BROWSER: &str = "HUD_DEFAULT_BROWSER";
+
+/// Server side values used for the parameters a customer left out
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionDefaults {
+    #[serde(with = "duration_str")]
+    pub session_time: Duration,
+    pub browser: Browser,
+    /// Configured in their own section, see [`crate::config::Config`]
+    #[serde(skip)]
+    pub timeouts: Timeouts,
+    /// Route used instead of the default one, set per listener
+    #[serde(skip)]
+    pub route: Option<String>,
+}
+
+impl Default for SessionDefaults {
+    fn default() -> Self {
+        Self {
+            session_time: Duration::from_secs(10 * 60),
+            browser: Browser::default(),
+            timeouts: Timeouts::default(),
+            route: None,
+        }
+    }
+}
+
+impl SessionDefaults {
+    /// Overrides the defaults with `HUD_DEFAULT_SESSION_TIME` (e.g. `30m`) and
+    /// `HUD_DEFAULT_BROWSER`
+    pub fn with_env(self) -> Result<Self, ConfigError> {
+        Ok(Self {
+            session_time: vars::duration(DEFAULT_SESSION_TIME)?.unwrap_or(self.session_time),
+            browser: vars::parse_with(DEFAULT_BROWSER, Browser::from_name)?.unwrap_or(self.browser),
+            ..self
+        })
+    }
+}
+
+/// Represents an active connection to the proxy that has included correctly
+/// formatted information
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Session {
+    addr: SocketAddr,
+    session_data: SessionData,
+    /// Empty for sessions restored from disk
+    password: Secret<String>,
+    /// The digest of the password, only known for sessions restored from disk
+    restored_digest: Option<String>,
+}
+
+/// What is persisted of a [`Session`]. The password is only kept as a keyed
+/// digest, enough to find the clients of the session again but not to
+/// authenticate with
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredSession {
+    addr: SocketAddr,
+    session_data: SessionData,
+    password_digest: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SessionData {
+    customer: String,
+    session_id: Option<String>,
+    country: Option<String>,
+    session_time: Duration,
+    /// The browser requested in the username
+    browser: Option<Browser>,
+    /// The browser used when none was requested
+    default_browser: Browser,
+    /// The total timeout requested in the username
+    timeout: Option<Duration>,
+    default_timeouts: Timeouts,
+    customer_timeouts: TimeoutOverrides,
+    /// Whether a sticky session may switch exits when its upstream fails, as
+    /// requested in the username
+    failover: Option<bool>,
+    customer_failover: bool,
+    /// The route used when neither the country nor the customer has one
+    #[serde(default)]
+    default_route: Option<String>,
+}
+
+impl SessionData {
+    /// A rotating session of the customer, with every parameter left out
+    fn new(customer: &str, defaults: &SessionDefaults) -> Self {
+        Self {
+            customer: customer.to_string(),
+            session_id: None,
+            country: None,
+            session_time: defaults.session_time,
+            browser: None,
+            default_browser: defaults.browser,
+            timeout: None,
+            default_timeouts: defaults.timeouts,
+            customer_timeouts: TimeoutOverrides::default(),
+            failover: None,
+            customer_failover: false,
+            default_route: defaults.route.clone(),
+        }
+    }
+
+    fn from_params(customer: &str, params: &Params, defaults: &SessionDefaults) -> Self {
+        Self {
+            session_id: params.str(SESSION_ID).map(ToString::to_string),
+            country: params.str(COUNTRY).map(ToString::to_string),
+            session_time: params
+                .duration(SESSION_TIME)
+                .unwrap_or(defaults.session_time),
+            browser: params.str(BROWSER).and_then(Browser::from_name),
+            timeout: params
+                .duration(TIMEOUT)
+                .filter(|timeout| !timeout.is_zero()),
+            failover: params.bool(FAILOVER),
+            ..Self::new(customer, defaults)
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Could not parse session data")]
+pub struct ParseAuthError;
+
+/// Decodes the `user:password` pair of a Basic Proxy-Authorization header
+fn decode_credentials(auth_header_str: &str) -> Result<String, ParseAuthError> {
+    let base64_auth: String = auth_header_str
+        .chars()
+        .skip(BASIC_AUTH_PREFIX.len())
+        .collect();
+
+    let decoded = base64::decode(base64_auth)
+        .into_report()
+        .change_context(ParseAuthError)?;
+
+    String::from_utf8(decoded)
+        .into_report()
+        .change_context(ParseAuthError)
+}
+
+fn split_credentials(creds: &str) -> Result<(&str, &str), ParseAuthError> {
+    creds.rsplit_once(':').ok_or_else(|| {
+        Report::new(ParseAuthError)
+            .attach_printable("Credentials are not correctly formatted, expected user:password")
+    })
+}
+
+/// Parses a username in the form `key-value-key-value` against the schema
+fn parse_username(schema: &ParamSchema, username: &str) -> Result<Params, ParseAuthError> {
+    // An empty username carries no parameters
+    let username_split = username.split('-').filter(|_| !username.is_empty());
+
+    let count = username_split.clone().count();
+
+    if count % 2 != 0 {
+        bail!(Report::new(ParseAuthError).attach_printable(format!(
+            "Expected even number of elements in username, found {count}"
+        ),));
+    }
+
+    schema
+        .parse(username_split.tuples::<(_, _)>())
+        .change_context(ParseAuthError)
+}
+#[allow(dead_code)]
+impl Session {
+    /// Creates a new session struct based on the information provided by the
+    /// Proxy-Authorization header
+    pub(super) fn new(
+        ctx: &HttpContext,
+        auth_header_str: &str,
+        defaults: &SessionDefaults,
+    ) -> Result<Self, ParseAuthError> {
+        let decoded = decode_credentials(auth_header_str)?;
+        let (username, password) = split_credentials(&decoded)?;
+
+        let params = parse_username(&SESSION_PARAMS, username)?;
+
+        check_param_length(password, 1, MAX_PASSWORD_LEN)
+            .attach_printable("password")
+            .change_context(ParseAuthError)?;
+
+        // The schema guarantees that required parameters are present
+        let customer = params.str(CUSTOMER).unwrap_or_default();
+
+        Ok(Self {
+            addr: ctx.client_addr,
+            session_data: SessionData::from_params(customer, &params, defaults),
+            password: Secret::new(password.to_string()),
+            restored_digest: None,
+        })
+    }
+
+    /// A session for listeners that don't require authentication, which
+    /// always belongs to `customer`. If a Proxy-Authorization header is sent,
+    /// the other username parameters are honored and the password is ignored
+    pub(super) fn anonymous(
+        ctx: &HttpContext,
+        customer: &str,
+        auth_header_str: Option<&str>,
+        defaults: &SessionDefaults,
+    ) -> Result<Self, ParseAuthError> {
+        let session_data = match auth_header_str {
+            Some(auth_header_str) => {
+                let decoded = decode_credentials(auth_header_str)?;
+                let (username, _) = split_credentials(&decoded)?;
+                let params = parse_username(&ANONYMOUS_PARAMS, username)?;
+
+                SessionData::from_params(customer, &params, defaults)
+            }
+            None => SessionData::new(customer, defaults),
+        };
+
+        Ok(Self {
+            addr: ctx.client_addr,
+            session_data,
+            password: Secret::new(String::new()),
+            restored_digest: None,
+        })
+    }
+
+    /// Keeps everything but the password, which is replaced by its digest
+    pub fn to_stored(&self, key: &DigestKey) -> StoredSession {
+        StoredSession {
+            addr: self.addr,
+            session_data: self.session_data.clone(),
+            password_digest: self.password_digest(key),
+        }
+    }
+
+    /// A session restored from disk. Its password isn't known, the client has
+    /// to authenticate again with its next CONNECT
+    pub fn from_stored(stored: StoredSession) -> Self {
+        Self {
+            addr: stored.addr,
+            session_data: stored.session_data,
+            password: Secret::new(String::new()),
+            restored_digest: Some(stored.password_digest),
+        }
+    }
+
+    /// The keyed digest of the password, which tells the clients of sessions
+    /// with different passwords apart
+    pub fn password_digest(&self, key: &DigestKey) -> String {
+        match &self.restored_digest {
+            Some(digest) => digest.clone(),
+            None => key.digest(self.password.expose()),
+        }
+    }
+
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    pub fn customer(&self) -> &str {
+        &self.session_data.customer
+    }
+
+    /// The id of a sticky session, [`None`] for rotating sessions
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_data.session_id.as_deref()
+    }
+
+    /// Sticky sessions keep using the same client, rotating ones get a new
+    /// client for every request
+    pub fn is_sticky(&self) -> bool {
+        self.session_data.session_id.is_some()
+    }
+
+    /// The requested exit country, [`None`] if any country will do
+    pub fn country(&self) -> Option<&str> {
+        self.session_data.country.as_deref()
+    }
+
+    pub fn session_time(&self) -> Duration {
+        self.session_data.session_time
+    }
+
+    /// The route of the listener the session was opened through, used when
+    /// neither the country nor the customer has one
+    pub fn default_route(&self) -> Option<&str> {
+        self.session_data.default_route.as_deref()
+    }
+
+    /// The browser profile clients of this session impersonate
+    pub fn browser(&self) -> Browser {
+        self.session_data
+            .browser
+            .unwrap_or(self.session_data.default_browser)
+    }
+
+    /// The timeouts for requests of this session, given the ones of the route
+    /// it was mapped to. The customer and the username take precedence over
+    /// the route
+    pub fn timeouts(&self, route: &TimeoutOverrides) -> Timeouts {
+        let requested = TimeoutOverrides {
+            total: self.session_data.timeout,
+            ..TimeoutOverrides::default()
+        };
+
+        self.session_data
+            .default_timeouts
+            .with(route)
+            .with(&self.session_data.customer_timeouts)
+            .with(&requested)
+    }
+
+    /// Rotating sessions may always be moved to another upstream, sticky ones
+    /// only if the username or the customer opted in
+    pub fn allows_failover(&self) -> bool {
+        !self.is_sticky()
+            || self
+                .session_data
+                .failover
+                .unwrap_or(self.session_data.customer_failover)
+    }
+
+    /// Applies the defaults of the authenticated customer
+    pub(super) fn apply_profile(&mut self, profile: &CustomerProfile) {
+        if let Some(browser) = profile.browser {
+            self.session_data.default_browser = browser;
+        }
+
+        self.session_data.customer_timeouts = profile.timeouts;
+        self.session_data.customer_failover = profile.failover;
+    }
+
+    pub fn password(&self) -> &str {
+        self.password.expose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymous_usernames_cannot_pick_the_customer() {
+        assert!(parse_username(&ANONYMOUS_PARAMS, "customer-acme").is_err());
+        assert!(parse_username(&ANONYMOUS_PARAMS, "session-abc-customer-acme").is_err());
+
+        let params = parse_username(&ANONYMOUS_PARAMS, "session-abc-country-us").unwrap();
+        assert_eq!(params.str(SESSION_ID), Some("abc"));
+        assert_eq!(params.str(COUNTRY), Some("US"));
+        assert_eq!(params.str(CUSTOMER), None);
+    }
+
+    #[test]
+    fn empty_usernames_carry_no_parameters() {
+        assert!(parse_username(&ANONYMOUS_PARAMS, "").is_ok());
+        assert!(parse_username(&SESSION_PARAMS, "").is_err());
+        assert!(parse_username(&ANONYMOUS_PARAMS, "session").is_err());
+    }
+}
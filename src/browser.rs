@@ -0,0 +1,39 @@
+//! The browser fingerprints clients can impersonate
+
+use reqwest_impersonate::{browser::ChromeVersion, ClientBuilder};
+use serde::{Deserialize, Serialize};
+
+/// A browser profile supported by reqwest-impersonate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Browser {
+    #[default]
+    Chrome104,
+}
+
+impl Browser {
+    /// Every profile, in the form used by the `browser` username parameter
+    pub const NAMES: &'static [&'static str] = &["chrome104"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "chrome104" => Some(Self::Chrome104),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Chrome104 => "chrome104",
+        }
+    }
+
+    /// Creates a client builder that impersonates this browser
+    pub fn client_builder(self) -> ClientBuilder {
+        let builder = reqwest_impersonate::Client::builder();
+
+        match self {
+            Self::Chrome104 => builder.chrome_builder(ChromeVersion::V104),
+        }
+    }
+}
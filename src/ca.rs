@@ -1,65 +1,224 @@
-use std::{fs, path::Path};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use error_stack::{bail, IntoReport, Report, Result, ResultExt};
 use hudsucker::rustls;
-use log::{error, info};
+use log::info;
 use rcgen::{
     BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa,
-    KeyUsagePurpose,
+    KeyUsagePurpose, SignatureAlgorithm,
 };
 use rustls_pemfile as pemfile;
+use thiserror::Error;
 
-pub fn acquire_ca() -> (rustls::PrivateKey, rustls::Certificate) {
-    create_ca_if_not_exist();
+use crate::config::CaConfig;
 
-    let private_key = rustls::PrivateKey(
-        pemfile::pkcs8_private_keys(&mut fs::read("cer/ca.key").unwrap().as_slice())
-            .expect("Failed to parse private key")
-            .remove(0),
-    );
+const DEFAULT_NAME: &str = "hud-proxy";
+const DEFAULT_COUNTRY: &str = "US";
+const DEFAULT_STATE: &str = "NY";
+const DEFAULT_LOCALITY: &str = "NYC";
+const DEFAULT_VALIDITY_DAYS: u64 = 3650;
+/// A hundred years, later dates are rejected by some clients anyway
+const MAX_VALIDITY_DAYS: u64 = 36_500;
 
-    let ca_cert = rustls::Certificate(
-        pemfile::certs(&mut fs::read("cer/ca.crt").unwrap().as_slice())
-            .expect("Failed to parse CA certificate")
-            .remove(0),
-    );
+#[derive(Debug, Error)]
+pub enum CaError {
+    #[error("Could not load the certificate authority")]
+    Load,
+    #[error("Could not generate the certificate authority")]
+    Generate,
+    #[error("Could not export the certificate authority")]
+    Export,
+}
 
-    (private_key, ca_cert)
+/// The key algorithms available for a new CA, limited to the ones browsers
+/// accept for TLS
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum KeyType {
+    EcdsaP256,
+    EcdsaP384,
 }
 
-fn create_ca_if_not_exist() {
-    if !Path::new("cer/ca.crt").exists() || !Path::new("cer/ca.key").exists() {
-        let ca = gen_ca();
+impl KeyType {
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        match self {
+            Self::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+            Self::EcdsaP384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+        }
+    }
+}
 
-        if let Err(err) = fs::create_dir_all("cer") {
-            error!("Cert folder creation failed: {}", err);
-        };
+/// The formats the CA certificate can be exported in
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ExportFormat {
+    Pem,
+    Der,
+    /// A bundle of the certificate and its private key
+    Pkcs12,
+}
 
-        if let Err(err) = fs::write("cer/ca.crt", ca.cert) {
-            error!("Cert file write failed: {}", err);
-        }
+/// The subject, validity and key of a new CA
+#[derive(Debug, Clone, clap::Args)]
+pub struct CaOptions {
+    #[clap(long, default_value = DEFAULT_NAME)]
+    pub common_name: String,
+    #[clap(long, default_value = DEFAULT_NAME)]
+    pub organization: String,
+    #[clap(long, default_value = DEFAULT_COUNTRY)]
+    pub country: String,
+    #[clap(long, default_value = DEFAULT_STATE)]
+    pub state: String,
+    #[clap(long, default_value = DEFAULT_LOCALITY)]
+    pub locality: String,
+    /// How long the CA is valid for, starting now
+    #[clap(
+        long,
+        default_value_t = DEFAULT_VALIDITY_DAYS,
+        value_parser = clap::value_parser!(u64).range(1..=MAX_VALIDITY_DAYS)
+    )]
+    pub validity_days: u64,
+    #[clap(long, value_enum, default_value = "ecdsa-p256")]
+    pub key_type: KeyType,
+}
 
-        if let Err(err) = fs::write("cer/ca.key", ca.key) {
-            error!("Private key file write failed: {}", err);
+impl Default for CaOptions {
+    fn default() -> Self {
+        Self {
+            common_name: DEFAULT_NAME.to_string(),
+            organization: DEFAULT_NAME.to_string(),
+            country: DEFAULT_COUNTRY.to_string(),
+            state: DEFAULT_STATE.to_string(),
+            locality: DEFAULT_LOCALITY.to_string(),
+            validity_days: DEFAULT_VALIDITY_DAYS,
+            key_type: KeyType::EcdsaP256,
         }
+    }
+}
+
+/// Loads the CA, generating one first if it doesn't exist
+pub fn acquire_ca(config: &CaConfig) -> Result<(rustls::PrivateKey, rustls::Certificate), CaError> {
+    create_ca_if_not_exist(config)?;
+
+    load_ca(config)
+}
+
+/// Reads the private key and the certificate of the CA
+pub fn load_ca(config: &CaConfig) -> Result<(rustls::PrivateKey, rustls::Certificate), CaError> {
+    let private_key = read_pem(&config.key, pemfile::pkcs8_private_keys)?;
+    let ca_cert = read_pem(&config.cert, pemfile::certs)?;
+
+    Ok((
+        rustls::PrivateKey(private_key),
+        rustls::Certificate(ca_cert),
+    ))
+}
+
+fn create_ca_if_not_exist(config: &CaConfig) -> Result<(), CaError> {
+    if !config.cert.exists() || !config.key.exists() {
+        generate_ca(config, &CaOptions::default())?;
 
         info!("A certificate has been generated, please ensure it is trusted by the operating system.");
     }
+
+    Ok(())
 }
 
-struct CAInfo {
-    key: String,
-    cert: String,
+/// Creates a new CA at the configured paths, replacing any existing one
+pub fn generate_ca(config: &CaConfig, options: &CaOptions) -> Result<(), CaError> {
+    let cert = gen_ca(options)?;
+
+    let cert_pem = cert
+        .serialize_pem()
+        .into_report()
+        .change_context(CaError::Generate)?;
+
+    for folder in [&config.cert, &config.key]
+        .iter()
+        .filter_map(|path| path.parent())
+    {
+        fs::create_dir_all(folder)
+            .into_report()
+            .attach_printable_lazy(|| format!("Path: {}", folder.display()))
+            .change_context(CaError::Generate)?;
+    }
+
+    write_file(&config.cert, cert_pem.as_bytes(), false)?;
+    write_file(
+        &config.key,
+        cert.serialize_private_key_pem().as_bytes(),
+        true,
+    )?;
+
+    Ok(())
 }
 
-fn gen_ca() -> CAInfo {
+/// Encodes the CA certificate, PKCS#12 bundles also contain the private key
+/// and must be protected by a `password`
+pub fn export_ca(
+    config: &CaConfig,
+    format: ExportFormat,
+    password: Option<&str>,
+) -> Result<Vec<u8>, CaError> {
+    let (private_key, ca_cert) = load_ca(config).change_context(CaError::Export)?;
+
+    match format {
+        ExportFormat::Pem => Ok(encode_pem("CERTIFICATE", &ca_cert.0).into_bytes()),
+        ExportFormat::Der => Ok(ca_cert.0),
+        ExportFormat::Pkcs12 => {
+            let password = password
+                .filter(|password| !password.is_empty())
+                .ok_or_else(|| {
+                    Report::new(CaError::Export)
+                        .attach_printable("A password is required to bundle the private key")
+                })?;
+
+            let pfx = p12::PFX::new(&ca_cert.0, &private_key.0, None, password, DEFAULT_NAME);
+
+            match pfx {
+                Some(pfx) => Ok(pfx.to_der()),
+                None => bail!(Report::new(CaError::Export)
+                    .attach_printable("The private key could not be bundled")),
+            }
+        }
+    }
+}
+
+/// Writes an exported CA to `path`, only the owner may read PKCS#12 bundles
+/// as they contain the private key
+pub fn write_export(path: &Path, format: ExportFormat, contents: &[u8]) -> Result<(), CaError> {
+    write_file(path, contents, matches!(format, ExportFormat::Pkcs12))
+        .change_context(CaError::Export)
+}
+
+fn gen_ca(options: &CaOptions) -> Result<Certificate, CaError> {
+    if !(1..=MAX_VALIDITY_DAYS).contains(&options.validity_days) {
+        bail!(Report::new(CaError::Generate).attach_printable(format!(
+            "The validity must be between 1 and {MAX_VALIDITY_DAYS} days"
+        )));
+    }
+
     let mut params = CertificateParams::default();
     let mut dn = DistinguishedName::new();
-    dn.push(DnType::CommonName, "hud-proxy");
-    dn.push(DnType::OrganizationName, "hud-proxy");
-    dn.push(DnType::CountryName, "US");
-    dn.push(DnType::StateOrProvinceName, "NY");
-    dn.push(DnType::LocalityName, "NYC");
+    dn.push(DnType::CommonName, options.common_name.as_str());
+    dn.push(DnType::OrganizationName, options.organization.as_str());
+    dn.push(DnType::CountryName, options.country.as_str());
+    dn.push(DnType::StateOrProvinceName, options.state.as_str());
+    dn.push(DnType::LocalityName, options.locality.as_str());
 
+    // rcgen takes its dates from the `time` crate, counting from the epoch
+    // avoids depending on it directly
+    let now = rcgen::date_time_ymd(1970, 1, 1)
+        + SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+    params.alg = options.key_type.algorithm();
+    params.not_before = now;
+    params.not_after = now + Duration::from_secs(options.validity_days * 24 * 60 * 60);
     params.distinguished_name = dn;
     params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
     params.key_usages = vec![
@@ -67,12 +226,73 @@ fn gen_ca() -> CAInfo {
         KeyUsagePurpose::KeyCertSign,
         KeyUsagePurpose::CrlSign,
     ];
-    let cert = Certificate::from_params(params).unwrap();
-    let cert_crt = cert.serialize_pem().unwrap();
-    let key = cert.serialize_private_key_pem();
 
-    CAInfo {
-        key,
-        cert: cert_crt,
+    Certificate::from_params(params)
+        .into_report()
+        .change_context(CaError::Generate)
+}
+
+/// Reads the first item of a PEM file
+fn read_pem(
+    path: &Path,
+    parse: fn(&mut dyn std::io::BufRead) -> std::io::Result<Vec<Vec<u8>>>,
+) -> Result<Vec<u8>, CaError> {
+    let contents = fs::read(path)
+        .into_report()
+        .attach_printable_lazy(|| format!("Path: {}", path.display()))
+        .change_context(CaError::Load)?;
+
+    let mut items = parse(&mut contents.as_slice())
+        .into_report()
+        .attach_printable_lazy(|| format!("Path: {}", path.display()))
+        .change_context(CaError::Load)?;
+
+    if items.is_empty() {
+        bail!(Report::new(CaError::Load)
+            .attach_printable(format!("Nothing found in {}", path.display())));
+    }
+
+    Ok(items.remove(0))
+}
+
+fn write_file(path: &Path, contents: &[u8], private: bool) -> Result<(), CaError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    if private {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
     }
+
+    options
+        .open(path)
+        .and_then(|mut file| {
+            // The mode only applies to new files
+            #[cfg(unix)]
+            if private {
+                use std::os::unix::fs::PermissionsExt;
+
+                file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            }
+
+            file.write_all(contents)
+        })
+        .into_report()
+        .attach_printable_lazy(|| format!("Path: {}", path.display()))
+        .change_context(CaError::Generate)
+}
+
+fn encode_pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+
+    let mut pem = format!("-----BEGIN {label}-----\n");
+
+    for line in encoded.as_bytes().chunks(64) {
+        // Base64 is always valid ASCII
+        pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+        pem.push('\n');
+    }
+
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
 }
@@ -0,0 +1,113 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    auth::HashAlgorithm,
+    ca::{CaOptions, ExportFormat},
+    config::Config,
+    route::RoutesSection,
+};
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about)]
+pub struct Cli {
+    /// Path to the configuration file, `HUD_CONFIG` is used if omitted
+    #[clap(long, short, global = true)]
+    pub config: Option<PathBuf>,
+    #[clap(flatten)]
+    pub overrides: ConfigOverrides,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the proxy, this is the default when no subcommand is given
+    Run,
+    /// Generate a new certificate authority at the configured paths without
+    /// starting the proxy
+    GenCa {
+        #[clap(flatten)]
+        options: CaOptions,
+        /// Replace the existing certificate authority
+        #[clap(long)]
+        force: bool,
+    },
+    /// Print the certificate of the certificate authority, e.g. to add it to a
+    /// trust store
+    ExportCa {
+        #[clap(long, value_enum, default_value = "pem")]
+        format: ExportFormat,
+        /// Password protecting the PKCS#12 bundle, required for that format
+        #[clap(long, required_if_eq("format", "pkcs12"))]
+        password: Option<String>,
+        /// Write to a file instead of stdout
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Check the configuration, the routes and the users, exiting with a
+    /// non-zero status if anything is wrong
+    CheckConfig,
+    /// Hash a password so it can be added to the users file
+    HashPassword {
+        /// The algorithm to hash the password with
+        #[clap(long, value_enum, default_value = "argon2id")]
+        algorithm: HashAlgorithm,
+        /// The password to hash, read from stdin if omitted
+        password: Option<String>,
+    },
+}
+
+/// Settings that take precedence over both the configuration file and the
+/// environment
+#[derive(Debug, Clone, Args)]
+pub struct ConfigOverrides {
+    /// Address the proxy listens on when no listeners are configured
+    #[clap(long, alias = "listen", global = true)]
+    pub bind: Option<SocketAddr>,
+    /// Path to the CA certificate, generated along with the key if missing
+    #[clap(long, global = true)]
+    pub ca_cert: Option<PathBuf>,
+    /// Path to the CA private key
+    #[clap(long, global = true)]
+    pub ca_key: Option<PathBuf>,
+    /// Path to a routes file, replacing the configured routes
+    #[clap(long, global = true)]
+    pub routes_file: Option<PathBuf>,
+    /// Path to a users file, replacing the configured users
+    #[clap(long, global = true)]
+    pub users_file: Option<PathBuf>,
+    /// A log filter in the format of `RUST_LOG`, e.g. `error,hud=debug`
+    #[clap(long, global = true)]
+    pub log_level: Option<String>,
+}
+
+impl ConfigOverrides {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(bind) = self.bind {
+            config.server.bind = bind;
+        }
+
+        if let Some(cert) = &self.ca_cert {
+            config.ca.cert = cert.clone();
+        }
+
+        if let Some(key) = &self.ca_key {
+            config.ca.key = key.clone();
+        }
+
+        if let Some(path) = &self.routes_file {
+            config.routes = RoutesSection::from_file(path.clone());
+        }
+
+        if let Some(path) = &self.users_file {
+            config.auth.users_file = Some(path.clone());
+            config.auth.users.clear();
+        }
+
+        if let Some(level) = &self.log_level {
+            config.logging.level = level.clone();
+        }
+    }
+}
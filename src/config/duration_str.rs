@@ -0,0 +1,16 @@
+//! Durations in the configuration file are written like the durations of the
+//! username parameters, e.g. `500ms`, `30s` or `2h`
+
+use std::time::Duration;
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::auth::parse_duration;
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+
+    parse_duration(&raw)
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| de::Error::custom(format!("\"{raw}\" is not a valid duration")))
+}
@@ -0,0 +1,520 @@
+//! The typed configuration of the proxy. Every setting has a default, which
+//! the configuration file overrides, followed by the `HUD_*` environment
+//! variables and finally the command line flags
+
+use std::{
+    collections::HashSet,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use error_stack::{bail, IntoReport, Report, Result, ResultExt};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    auth::{AuthConfig, SessionDefaults},
+    browser::Browser,
+    cli::ConfigOverrides,
+    proxy::RetryPolicy,
+    route::{HealthConfig, RoutesSection},
+    timeouts::{TimeoutOverrides, Timeouts},
+};
+
+pub mod duration_str;
+pub mod vars;
+
+/// Path to the configuration file, only the defaults and the environment are
+/// used if unset
+const CONFIG_FILE: &str = "HUD_CONFIG";
+const BIND: &str = "HUD_BIND";
+const DRAIN_TIMEOUT_SECS: &str = "HUD_DRAIN_TIMEOUT_SECS";
+const CA_CERT: &str = "HUD_CA_CERT";
+const CA_KEY: &str = "HUD_CA_KEY";
+const CA_CACHE_SIZE: &str = "HUD_CA_CACHE_SIZE";
+const LOG_LEVEL: &str = "RUST_LOG";
+const MAX_CLIENTS: &str = "HUD_MAX_CLIENTS";
+const MAX_SESSIONS: &str = "HUD_MAX_SESSIONS";
+const SWEEP_INTERVAL_SECS: &str = "HUD_SWEEP_INTERVAL_SECS";
+/// Path to the snapshot, nothing is persisted if unset
+const STATE_FILE: &str = "HUD_STATE_FILE";
+const SNAPSHOT_INTERVAL_SECS: &str = "HUD_SNAPSHOT_INTERVAL_SECS";
+const RELOAD_WATCH: &str = "HUD_RELOAD_WATCH";
+const RELOAD_INTERVAL_SECS: &str = "HUD_RELOAD_INTERVAL_SECS";
+
+#[derive(Debug, Error)]
+#[error("Invalid configuration")]
+pub struct ConfigError;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    /// Every address the proxy listens on, only `server.bind` is used if empty
+    pub listeners: Vec<ListenerConfig>,
+    pub ca: CaConfig,
+    pub logging: LoggingConfig,
+    pub auth: AuthConfig,
+    pub session: SessionDefaults,
+    /// Server wide timeouts, routes, customers and sessions may override them
+    pub timeouts: TimeoutOverrides,
+    pub retry: RetryPolicy,
+    pub storage: StorageConfig,
+    pub routes: RoutesSection,
+    /// Health checks of the pool members, disabled if omitted
+    pub health: Option<HealthConfig>,
+    pub reload: ReloadConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the proxy listens on when no listeners are configured
+    pub bind: SocketAddr,
+    /// How long in-flight requests may take to finish on shutdown
+    #[serde(with = "duration_str")]
+    pub drain_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// An address the proxy listens on, along with the policy of the sessions
+/// opened through it. Every listener shares the same storage and CA
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Shown in the logs, and the customer of sessions opened without
+    /// credentials
+    pub name: String,
+    pub bind: SocketAddr,
+    /// Whether clients must authenticate, the username parameters are still
+    /// honored if they don't have to
+    #[serde(default = "default_auth")]
+    pub auth: bool,
+    /// Route used instead of the default one
+    pub route: Option<String>,
+    /// Browser used when neither the username nor the customer picks one
+    pub browser: Option<Browser>,
+}
+
+impl ListenerConfig {
+    /// The listener used when none are configured
+    fn from_bind(bind: SocketAddr) -> Self {
+        Self {
+            name: "default".to_string(),
+            bind,
+            auth: true,
+            route: None,
+            browser: None,
+        }
+    }
+
+    /// The server wide session defaults, with the route and browser of this
+    /// listener
+    pub fn session_defaults(&self, defaults: &SessionDefaults) -> SessionDefaults {
+        SessionDefaults {
+            browser: self.browser.unwrap_or(defaults.browser),
+            route: self.route.clone(),
+            ..defaults.clone()
+        }
+    }
+}
+
+fn default_auth() -> bool {
+    true
+}
+
+/// The certificate authority used to intercept TLS, generated on the first
+/// start if the files don't exist
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Leaf certificates kept around instead of signing one per connection
+    pub cache_size: u64,
+}
+
+impl Default for CaConfig {
+    fn default() -> Self {
+        Self {
+            cert: PathBuf::from("cer/ca.crt"),
+            key: PathBuf::from("cer/ca.key"),
+            cache_size: 1_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// A filter in the format of `RUST_LOG`, e.g. `error,hud=debug`
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "error,hud=info".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Upper bound for the clients kept alive at once
+    pub max_clients: usize,
+    /// Upper bound for the sessions kept alive at once
+    pub max_sessions: usize,
+    /// How often expired entries are dropped
+    #[serde(with = "duration_str")]
+    pub sweep_interval: Duration,
+    /// Where sessions are persisted across restarts, nothing is persisted if
+    /// unset. The key of the password digests is kept next to it, with a
+    /// `.key` extension
+    pub state_file: Option<PathBuf>,
+    #[serde(with = "duration_str")]
+    pub snapshot_interval: Duration,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            max_clients: 10_000,
+            max_sessions: 10_000,
+            sweep_interval: Duration::from_secs(30),
+            state_file: None,
+            snapshot_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Authentication, routes, session defaults and retries can be reloaded while
+/// the proxy runs, the other settings only apply after a restart
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReloadConfig {
+    /// Whether changes to the configuration, routes and users files trigger a
+    /// reload, SIGHUP always does
+    pub watch: bool,
+    /// How often the watched files are checked for changes
+    #[serde(with = "duration_str")]
+    pub interval: Duration,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        Self {
+            watch: true,
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Where the configuration comes from, kept around to load it again on
+/// reload
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    path: Option<PathBuf>,
+    overrides: ConfigOverrides,
+}
+
+impl ConfigSource {
+    /// Uses the file at `path`, falling back to the one in `HUD_CONFIG`
+    pub fn new(path: Option<PathBuf>, overrides: ConfigOverrides) -> Self {
+        Self {
+            path: path.or_else(|| vars::string(CONFIG_FILE).map(PathBuf::from)),
+            overrides,
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Merges the defaults, the file, the environment and the command line
+    /// flags, in increasing order of precedence, and validates the result
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        let config = match &self.path {
+            Some(path) => Config::from_path(path)?,
+            None => Config::default(),
+        };
+
+        let mut config = config.with_env()?;
+        self.overrides.apply(&mut config);
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn from_path(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .into_report()
+            .attach_printable_lazy(|| format!("Path: {}", path.display()))
+            .change_context(ConfigError)?;
+
+        toml::from_str(&contents)
+            .into_report()
+            .attach_printable_lazy(|| format!("Path: {}", path.display()))
+            .change_context(ConfigError)
+    }
+
+    fn with_env(self) -> Result<Self, ConfigError> {
+        Ok(Self {
+            server: self.server.with_env()?,
+            listeners: self.listeners,
+            ca: self.ca.with_env()?,
+            logging: self.logging.with_env(),
+            auth: self.auth.with_env()?,
+            session: self.session.with_env()?,
+            timeouts: self.timeouts.with_env()?,
+            retry: self.retry.with_env()?,
+            storage: self.storage.with_env()?,
+            routes: self.routes.with_env()?,
+            health: HealthConfig::with_env(self.health)?,
+            reload: self.reload.with_env()?,
+        })
+    }
+
+    /// Checks the settings that can't be checked while parsing. Routes and
+    /// users are checked when they are loaded
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.ca.cache_size == 0 {
+            bail!(Report::new(ConfigError)
+                .attach_printable("The certificate cache size must be at least 1"));
+        }
+
+        if self.storage.max_clients == 0 {
+            bail!(Report::new(ConfigError)
+                .attach_printable("The client storage must allow at least 1 client"));
+        }
+
+        if self.storage.max_sessions == 0 {
+            bail!(Report::new(ConfigError)
+                .attach_printable("The session storage must allow at least 1 session"));
+        }
+
+        if self.auth.lockout.max_tracked == 0 {
+            bail!(Report::new(ConfigError)
+                .attach_printable("The lockout must track at least 1 IP and customer"));
+        }
+
+        if let Some(health) = &self.health {
+            if health.failure_threshold == 0 {
+                bail!(Report::new(ConfigError)
+                    .attach_printable("The health check failure threshold must be at least 1"));
+            }
+        }
+
+        let mut names = HashSet::new();
+        let mut binds = HashSet::new();
+
+        for listener in &self.listeners {
+            if !names.insert(&listener.name) {
+                bail!(Report::new(ConfigError)
+                    .attach_printable(format!("Duplicate listener \"{}\"", listener.name)));
+            }
+
+            if !binds.insert(listener.bind) {
+                bail!(Report::new(ConfigError).attach_printable(format!(
+                    "More than one listener binds to {}",
+                    listener.bind
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The configured listeners, or a single one on `server.bind` that
+    /// requires authentication
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if self.listeners.is_empty() {
+            vec![ListenerConfig::from_bind(self.server.bind)]
+        } else {
+            self.listeners.clone()
+        }
+    }
+
+    /// The files whose changes trigger a reload
+    pub fn watched_files(&self, source: &ConfigSource) -> Vec<PathBuf> {
+        source
+            .path()
+            .map(Path::to_path_buf)
+            .into_iter()
+            .chain(self.routes.file.clone())
+            .chain(self.auth.users_file.clone())
+            .collect()
+    }
+
+    /// The session defaults, along with the server wide timeouts
+    pub fn session_defaults(&self) -> SessionDefaults {
+        SessionDefaults {
+            timeouts: Timeouts::default().with(&self.timeouts),
+            ..self.session.clone()
+        }
+    }
+}
+
+impl ServerConfig {
+    fn with_env(self) -> Result<Self, ConfigError> {
+        Ok(Self {
+            bind: vars::parse(BIND)?.unwrap_or(self.bind),
+            drain_timeout: vars::secs(DRAIN_TIMEOUT_SECS)?.unwrap_or(self.drain_timeout),
+        })
+    }
+}
+
+impl CaConfig {
+    fn with_env(self) -> Result<Self, ConfigError> {
+        Ok(Self {
+            cert: vars::parse(CA_CERT)?.unwrap_or(self.cert),
+            key: vars::parse(CA_KEY)?.unwrap_or(self.key),
+            cache_size: vars::parse(CA_CACHE_SIZE)?.unwrap_or(self.cache_size),
+        })
+    }
+}
+
+impl LoggingConfig {
+    fn with_env(self) -> Self {
+        Self {
+            level: vars::string(LOG_LEVEL).unwrap_or(self.level),
+        }
+    }
+}
+
+impl StorageConfig {
+    fn with_env(self) -> Result<Self, ConfigError> {
+        Ok(Self {
+            max_clients: vars::parse(MAX_CLIENTS)?.unwrap_or(self.max_clients),
+            max_sessions: vars::parse(MAX_SESSIONS)?.unwrap_or(self.max_sessions),
+            sweep_interval: vars::secs(SWEEP_INTERVAL_SECS)?.unwrap_or(self.sweep_interval),
+            state_file: vars::parse(STATE_FILE)?.or(self.state_file),
+            snapshot_interval: vars::secs(SNAPSHOT_INTERVAL_SECS)?
+                .unwrap_or(self.snapshot_interval),
+        })
+    }
+}
+
+impl ReloadConfig {
+    fn with_env(self) -> Result<Self, ConfigError> {
+        Ok(Self {
+            watch: vars::parse(RELOAD_WATCH)?.unwrap_or(self.watch),
+            interval: vars::secs(RELOAD_INTERVAL_SECS)?.unwrap_or(self.interval),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(bind: Option<SocketAddr>) -> ConfigOverrides {
+        ConfigOverrides {
+            bind,
+            ca_cert: None,
+            ca_key: None,
+            routes_file: None,
+            users_file: None,
+            log_level: None,
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    // A single test, as the environment is shared by every test thread
+    #[test]
+    fn load_precedence() {
+        let path = std::env::temp_dir().join(format!("hud-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "[server]\nbind = \"127.0.0.1:4000\"\n\n[ca]\ncache_size = 50\n\n[storage]\nmax_clients = 20\n",
+        )
+        .unwrap();
+
+        let defaults = ConfigSource {
+            path: None,
+            overrides: overrides(None),
+        };
+        let file = ConfigSource {
+            path: Some(path.clone()),
+            overrides: overrides(None),
+        };
+        let cli = ConfigSource {
+            path: Some(path.clone()),
+            overrides: overrides(Some(addr(6000))),
+        };
+
+        let config = defaults.load().unwrap();
+        assert_eq!(config.server.bind, addr(3000));
+
+        let config = file.load().unwrap();
+        assert_eq!(config.server.bind, addr(4000));
+        assert_eq!(config.ca.cache_size, 50);
+        assert_eq!(config.storage.max_clients, 20);
+        // Untouched by the file
+        assert_eq!(config.storage.max_sessions, 10_000);
+
+        std::env::set_var(BIND, "127.0.0.1:5000");
+        std::env::set_var(MAX_CLIENTS, "30");
+
+        let config = file.load().unwrap();
+        assert_eq!(config.server.bind, addr(5000));
+        assert_eq!(config.storage.max_clients, 30);
+        assert_eq!(config.ca.cache_size, 50);
+
+        let config = cli.load().unwrap();
+        assert_eq!(config.server.bind, addr(6000));
+        assert_eq!(config.storage.max_clients, 30);
+        assert_eq!(config.ca.cache_size, 50);
+
+        std::env::set_var(MAX_CLIENTS, "0");
+        assert!(cli.load().is_err());
+
+        std::env::remove_var(BIND);
+        std::env::remove_var(MAX_CLIENTS);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn durations_reject_zero() {
+        assert!(toml::from_str::<StorageConfig>("sweep_interval = \"0s\"").is_err());
+        assert!(toml::from_str::<StorageConfig>("sweep_interval = \"0\"").is_err());
+
+        let config: StorageConfig = toml::from_str("sweep_interval = \"500ms\"").unwrap();
+        assert_eq!(config.sweep_interval, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn rejects_a_zero_failure_threshold() {
+        let config: Config =
+            toml::from_str("[health]\nurl = \"http://probe.example\"\nfailure_threshold = 0\n")
+                .unwrap();
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn listeners_fall_back_to_the_bind_address() {
+        let config = Config::default();
+        let listeners = config.listeners();
+
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].bind, config.server.bind);
+        assert!(listeners[0].auth);
+    }
+}
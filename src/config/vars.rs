@@ -0,0 +1,58 @@
+//! Typed access to the `HUD_*` environment variables, which take precedence
+//! over the configuration file. Variables that are set but can't be parsed
+//! are reported instead of being ignored
+
+use std::{env, str::FromStr, time::Duration};
+
+use error_stack::{Report, Result};
+
+use super::ConfigError;
+use crate::auth::parse_duration;
+
+/// The raw value of the variable, [`None`] if unset
+pub fn string(key: &str) -> Option<String> {
+    env::var(key).ok()
+}
+
+pub fn parse<T: FromStr>(key: &str) -> Result<Option<T>, ConfigError> {
+    parse_with(key, |value| value.parse().ok())
+}
+
+/// A duration written like the username parameters, e.g. `500ms` or `2m`
+pub fn duration(key: &str) -> Result<Option<Duration>, ConfigError> {
+    parse_with(key, |value| {
+        parse_duration(value).filter(|duration| !duration.is_zero())
+    })
+}
+
+/// A duration given as a whole number of seconds
+pub fn secs(key: &str) -> Result<Option<Duration>, ConfigError> {
+    parse_with(key, |value| {
+        value
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    })
+}
+
+/// A duration given as a whole number of milliseconds
+pub fn millis(key: &str) -> Result<Option<Duration>, ConfigError> {
+    parse_with(key, |value| value.parse().ok().map(Duration::from_millis))
+}
+
+pub fn parse_with<T, F: FnOnce(&str) -> Option<T>>(
+    key: &str,
+    f: F,
+) -> Result<Option<T>, ConfigError> {
+    let value = match env::var(key) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+
+    match f(&value) {
+        Some(parsed) => Ok(Some(parsed)),
+        None => Err(Report::new(ConfigError)
+            .attach_printable(format!("Invalid value \"{value}\" for {key}"))),
+    }
+}
@@ -0,0 +1,13 @@
+//! Holds functions to ease conversions between the hudsucker and reqwest types
+
+use thiserror::Error;
+
+mod request;
+mod response;
+
+pub use request::{request_hud_to_reqwest, BodySent};
+pub use response::response_reqwest_to_hud;
+
+#[derive(Debug, Error)]
+#[error("Could not convert between types")]
+pub struct ConversionError;
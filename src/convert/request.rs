@@ -0,0 +1,84 @@
+use error_stack::{bail, IntoReport, Report, Result, ResultExt};
+use hudsucker::hyper::{body::HttpBody, Body, Request};
+use log::debug;
+use reqwest_impersonate::Url;
+use tokio::sync::oneshot;
+
+use super::ConversionError;
+
+/// Resolves once the whole request body was handed to the client, right away
+/// for requests without one
+pub struct BodySent(Option<oneshot::Receiver<()>>);
+
+impl BodySent {
+    pub async fn wait(&mut self) {
+        if let Some(sent) = self.0.take() {
+            // The forwarding task is gone either way if the sender was dropped
+            let _ = sent.await;
+        }
+    }
+}
+
+/// Converts a hudsucker request to a reqwest one.
+///
+/// The body is handed over as a stream, so uploads are forwarded while they
+/// are still being received instead of being held in memory. A background
+/// task forwards every chunk and reports through [`BodySent`] once the last
+/// one was taken.
+pub fn request_hud_to_reqwest(
+    req: Request<Body>,
+) -> Result<(reqwest_impersonate::Request, BodySent), ConversionError> {
+    let (parts, body) = req.into_parts();
+
+    // Requests coming out of the MITM always carry an absolute uri
+    if parts.uri.scheme().is_none() || parts.uri.host().is_none() {
+        bail!(Report::new(ConversionError)
+            .attach_printable(format!("Expected an absolute uri, found \"{}\"", parts.uri)));
+    }
+
+    let url = Url::parse(&parts.uri.to_string())
+        .into_report()
+        .attach_printable_lazy(|| format!("Uri: {}", parts.uri))
+        .change_context(ConversionError)?;
+
+    let mut reqwest_req = reqwest_impersonate::Request::new(parts.method, url);
+
+    *reqwest_req.headers_mut() = parts.headers;
+    *reqwest_req.version_mut() = parts.version;
+
+    // Leave bodyless requests without one, otherwise they would be sent chunked
+    if body.is_end_stream() {
+        return Ok((reqwest_req, BodySent(None)));
+    }
+
+    let (mut sender, forwarded) = Body::channel();
+    let (sent_tx, sent_rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let mut body = body;
+
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        // The request to the host was dropped
+                        break;
+                    }
+                }
+                Err(err) => {
+                    debug!("The request body failed mid-stream: {err}");
+
+                    // Fails the upstream request instead of ending the body cleanly
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+
+        let _ = sent_tx.send(());
+    });
+
+    *reqwest_req.body_mut() = Some(reqwest_impersonate::Body::wrap_stream(forwarded));
+
+    Ok((reqwest_req, BodySent(Some(sent_rx))))
+}
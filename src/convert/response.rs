@@ -0,0 +1,62 @@
+use error_stack::{IntoReport, Result, ResultExt};
+use hudsucker::hyper::{Body, Response};
+use log::warn;
+
+use super::ConversionError;
+
+/// Converts a reqwest response to a hudsucker one.
+///
+/// The body is streamed through as it arrives, a background task forwards
+/// every chunk and only pulls the next one once hyper has accepted the last,
+/// so slow clients apply backpressure on the upstream. The `guard` is held by
+/// that task and dropped once the body is done.
+pub fn response_reqwest_to_hud<G: Send + 'static>(
+    mut reqwest_res: reqwest_impersonate::Response,
+    guard: G,
+) -> Result<Response<Body>, ConversionError> {
+    let mut builder = Response::builder()
+        .status(reqwest_res.status())
+        .version(reqwest_res.version());
+
+    // We are discarding the reqwest response anyways, so lets just be greedy
+    if let Some(headers) = builder.headers_mut() {
+        std::mem::swap(headers, reqwest_res.headers_mut());
+    }
+
+    if let Some(extensions) = builder.extensions_mut() {
+        std::mem::swap(extensions, reqwest_res.extensions_mut());
+    }
+
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let _guard = guard;
+
+        loop {
+            match reqwest_res.chunk().await {
+                Ok(Some(chunk)) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        // The client went away, dropping the response stops the download
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    warn!(
+                        "Upstream body for {} failed mid-stream: {err}",
+                        reqwest_res.url()
+                    );
+
+                    // Makes hyper reset the connection instead of ending the body cleanly
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+
+    builder
+        .body(body)
+        .into_report()
+        .change_context(ConversionError)
+}
@@ -1,50 +1,176 @@
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use clap::Parser;
+use color_eyre::eyre::eyre;
 use hudsucker::certificate_authority::RcgenAuthority;
-use log::info;
+use log::{error, info};
 
-use crate::proxy::ProxyWrapper;
+use crate::{
+    auth::HashAlgorithm,
+    ca::{CaOptions, ExportFormat},
+    cli::{Cli, Command},
+    config::{Config, ConfigSource, LoggingConfig},
+    proxy::{ProxyWrapper, Settings},
+};
 
 mod auth;
+mod browser;
 mod ca;
+mod cli;
+mod config;
 mod convert;
 mod proxy;
 mod response;
 mod route;
 mod storage;
-
-const RUST_LOG: &str = "RUST_LOG";
+mod timeouts;
 
 #[tokio::main]
-async fn main() -> color_eyre::Result<()> {
-    setup_logging()?;
+async fn main() -> color_eyre::Result<ExitCode> {
+    color_eyre::install()?;
+
+    let Cli {
+        config,
+        overrides,
+        command,
+    } = Cli::parse();
+
+    let source = ConfigSource::new(config, overrides);
+
+    match command.unwrap_or(Command::Run) {
+        Command::Run => return run(load_config(&source)?, source).await,
+        Command::GenCa { options, force } => gen_ca(&load_config(&source)?, options, force)?,
+        Command::ExportCa {
+            format,
+            password,
+            output,
+        } => export_ca(&load_config(&source)?, format, password, output)?,
+        Command::CheckConfig => check_config(&load_config(&source)?)?,
+        Command::HashPassword {
+            algorithm,
+            password,
+        } => {
+            setup_logging(&LoggingConfig::default());
+
+            hash_password(algorithm, password)?
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
 
+fn load_config(source: &ConfigSource) -> color_eyre::Result<Config> {
+    let config = source.load().map_err(|report| eyre!("{report:?}"))?;
+
+    setup_logging(&config.logging);
+
+    Ok(config)
+}
+
+async fn run(config: Config, source: ConfigSource) -> color_eyre::Result<ExitCode> {
     info!("Starting up proxy");
 
-    let (private_key, ca_cert) = ca::acquire_ca();
+    let settings = Settings::from_config(&config, &config.listeners())
+        .map_err(|report| eyre!("{report:?}"))?;
+
+    let (private_key, ca_cert) =
+        ca::acquire_ca(&config.ca).map_err(|report| eyre!("{report:?}"))?;
 
-    let ca = RcgenAuthority::new(private_key, ca_cert, 1_000)
-        .expect("Failed to create Certificate Authority");
+    let ca = RcgenAuthority::new(private_key, ca_cert, config.ca.cache_size)
+        .map_err(|err| eyre!("Failed to create the certificate authority: {err}"))?;
 
-    ProxyWrapper::new().start(ca).await;
+    match ProxyWrapper::new(&config, source, settings).start(ca).await {
+        Ok(()) => {
+            info!("Shut down cleanly");
+
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(report) => {
+            error!("{report:?}");
+
+            Ok(ExitCode::from(report.current_context().exit_code()))
+        }
+    }
+}
+
+fn gen_ca(config: &Config, options: CaOptions, force: bool) -> color_eyre::Result<()> {
+    let paths = &config.ca;
+
+    if !force && (paths.cert.exists() || paths.key.exists()) {
+        return Err(eyre!(
+            "A certificate authority already exists at {} and {}, pass --force to replace it",
+            paths.cert.display(),
+            paths.key.display()
+        ));
+    }
+
+    ca::generate_ca(paths, &options).map_err(|report| eyre!("{report:?}"))?;
+
+    println!(
+        "Generated a certificate authority at {} and {}",
+        paths.cert.display(),
+        paths.key.display()
+    );
 
     Ok(())
 }
 
-fn setup_logging() -> color_eyre::Result<()> {
-    // if std::env::var("RUST_LIB_BACKTRACE").is_err() {
-    //     std::env::set_var("RUST_LIB_BACKTRACE", "1");
-    // }
+fn export_ca(
+    config: &Config,
+    format: ExportFormat,
+    password: Option<String>,
+    output: Option<PathBuf>,
+) -> color_eyre::Result<()> {
+    let exported = ca::export_ca(&config.ca, format, password.as_deref())
+        .map_err(|report| eyre!("{report:?}"))?;
+
+    match output {
+        Some(path) => {
+            ca::write_export(&path, format, &exported).map_err(|report| eyre!("{report:?}"))?
+        }
+        None => std::io::stdout().lock().write_all(&exported)?,
+    }
+
+    Ok(())
+}
 
-    // if std::env::var("RUST_BACKTRACE").is_err() {
-    //     std::env::set_var("RUST_BACKTRACE", "1");
-    // }
+fn check_config(config: &Config) -> color_eyre::Result<()> {
+    Settings::from_config(config, &config.listeners()).map_err(|report| eyre!("{report:?}"))?;
 
-    if std::env::var(RUST_LOG).is_err() {
-        std::env::set_var(RUST_LOG, "error,hud=info");
+    if config.ca.cert.exists() && config.ca.key.exists() {
+        ca::load_ca(&config.ca).map_err(|report| eyre!("{report:?}"))?;
+    } else {
+        println!("No certificate authority found, one will be generated on start");
     }
 
-    env_logger::init();
+    println!("The configuration is valid");
 
-    color_eyre::install()?;
+    Ok(())
+}
+
+fn hash_password(algorithm: HashAlgorithm, password: Option<String>) -> color_eyre::Result<()> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(&['\r', '\n'][..]).to_string()
+        }
+    };
+
+    let hash = auth::hash_password(&password, algorithm).map_err(|report| eyre!("{report:?}"))?;
+
+    println!("{hash}");
 
     Ok(())
 }
+
+fn setup_logging(logging: &LoggingConfig) {
+    env_logger::Builder::new()
+        .parse_filters(&logging.level)
+        .init();
+}
@@ -0,0 +1,85 @@
+//! Counts the requests that are still being proxied. Requests inside a CONNECT
+//! tunnel are served on upgraded connections, which hyper's graceful shutdown
+//! doesn't wait for, so shutdown waits for this count to reach zero as well
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+pub struct InFlight {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl InFlight {
+    /// Counts a request until the returned guard is dropped
+    pub fn start(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::AcqRel);
+
+        InFlightGuard(self.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Resolves once no request is in flight
+    pub async fn wait_idle(&self) {
+        loop {
+            // Created before the check so a request finishing in between isn't missed
+            let idle = self.idle.notified();
+
+            if self.count() == 0 {
+                return;
+            }
+
+            idle.await;
+        }
+    }
+}
+
+/// Keeps a request counted as in flight while alive
+#[derive(Debug)]
+pub struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_every_guard() {
+        let in_flight = Arc::new(InFlight::default());
+        let first = in_flight.start();
+        let second = in_flight.start();
+        assert_eq!(in_flight.count(), 2);
+
+        drop(first);
+
+        let idle = tokio::time::timeout(Duration::from_millis(50), in_flight.wait_idle());
+        assert!(idle.await.is_err());
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(second);
+        });
+
+        tokio::time::timeout(Duration::from_secs(1), in_flight.wait_idle())
+            .await
+            .unwrap();
+        assert_eq!(in_flight.count(), 0);
+    }
+}
@@ -1,49 +1,309 @@
+mod in_flight;
 mod proxy_handler;
+mod reload;
+mod retry;
+mod upstream_error;
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
-use cached::async_sync::Mutex;
+use error_stack::{Report, Result, ResultExt};
 use hudsucker::{certificate_authority::RcgenAuthority, Proxy};
-use log::error;
+use log::{error, info, warn};
+use thiserror::Error;
+use tokio::sync::{mpsc, watch};
 
-use self::proxy_handler::ProxyHandler;
-use crate::storage::{ClientStorage, SessionStorage};
+use self::{
+    in_flight::InFlight,
+    proxy_handler::ProxyHandler,
+    reload::{Reloader, SharedSettings},
+};
+pub use self::{reload::Settings, retry::RetryPolicy};
+use crate::{
+    auth::{DigestKey, FailureTracker},
+    config::{Config, ConfigSource, ListenerConfig, ReloadConfig},
+    storage::{spawn_sweeper, ClientStorage, SessionStorage, StateFile, Sweep},
+};
+
+#[derive(Debug, Error)]
+pub enum ShutdownError {
+    #[error("The proxy stopped unexpectedly")]
+    Server,
+    #[error("Requests were still in flight when the drain deadline passed")]
+    DrainTimeout,
+    #[error("Could not save the state on shutdown")]
+    Flush,
+}
+
+impl ShutdownError {
+    /// The status the process exits with
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Server => 1,
+            Self::DrainTimeout => 2,
+            Self::Flush => 3,
+        }
+    }
+}
 
 // Wraps a proxy to provide an in-memory cache
 pub struct ProxyWrapper {
-    bind_addr: SocketAddr,
-    client_storage: Arc<Mutex<ClientStorage>>,
-    session_storage: Arc<Mutex<SessionStorage>>,
+    listeners: Vec<ListenerConfig>,
+    drain_timeout: Duration,
+    client_storage: Arc<ClientStorage>,
+    session_storage: Arc<SessionStorage>,
+    failure_tracker: Arc<FailureTracker>,
+    settings: Arc<SharedSettings>,
+    reloader: Arc<Reloader>,
+    reload: ReloadConfig,
+    sweep_interval: Duration,
+    state_file: Option<Arc<StateFile>>,
+    in_flight: Arc<InFlight>,
 }
 
 impl ProxyWrapper {
-    pub fn new() -> Self {
+    pub fn new(config: &Config, source: ConfigSource, settings: Settings) -> Self {
+        let storage = &config.storage;
+        let settings = Arc::new(SharedSettings::new(settings));
+
+        let state_file = storage
+            .state_file
+            .clone()
+            .map(|path| Arc::new(StateFile::new(path, storage.snapshot_interval)));
+
+        // Without the key of the previous run, restored sessions can't find their clients
+        let digest_key = match state_file.as_ref().map(|state_file| state_file.load_key()) {
+            Some(Ok(key)) => key,
+            Some(Err(err)) => {
+                error!("Could not load the digest key, sticky sessions won't be restored\n{err:?}");
+                DigestKey::generate()
+            }
+            None => DigestKey::generate(),
+        };
+
+        let listeners = config.listeners();
+
         Self {
-            client_storage: Arc::new(Mutex::new(ClientStorage::new())),
-            session_storage: Arc::new(Mutex::new(SessionStorage::new())),
-            bind_addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            client_storage: Arc::new(ClientStorage::new(storage.max_clients, digest_key)),
+            session_storage: Arc::new(SessionStorage::new(storage.max_sessions)),
+            drain_timeout: config.server.drain_timeout,
+            failure_tracker: Arc::new(FailureTracker::new(config.auth.lockout.clone())),
+            reloader: Arc::new(Reloader::new(
+                source,
+                config,
+                listeners.clone(),
+                settings.clone(),
+            )),
+            listeners,
+            settings,
+            sweep_interval: storage.sweep_interval,
+            state_file,
+            reload: config.reload.clone(),
+            in_flight: Arc::new(InFlight::default()),
+        }
+    }
+
+    /// Runs every listener until SIGINT or SIGTERM, then stops accepting
+    /// connections, waits for the in-flight requests up to the drain timeout
+    /// and saves the state
+    pub async fn start(&self, ca: RcgenAuthority) -> Result<(), ShutdownError> {
+        if let Some(state_file) = &self.state_file {
+            if let Err(err) = state_file.load(&self.session_storage, &self.client_storage) {
+                error!("Could not restore the previous state, starting fresh\n{err:?}");
+            }
+        }
+
+        let snapshots = self.state_file.as_ref().map(|state_file| {
+            state_file.spawn_snapshots(&self.session_storage, &self.client_storage)
+        });
+
+        let sweepers = [
+            spawn_sweeper("client", &self.client_storage, self.sweep_interval),
+            spawn_sweeper("session", &self.session_storage, self.sweep_interval),
+            spawn_sweeper("lockout", &self.failure_tracker, self.sweep_interval),
+        ];
+
+        // Also runs the health checks, which follow the reloaded routes
+        let reloads = self.reloader.clone().spawn(&self.reload);
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (stopped_tx, stopped_rx) = mpsc::unbounded_channel();
+
+        for listener in &self.listeners {
+            info!(
+                "Listener \"{}\" listening on {}",
+                listener.name, listener.bind
+            );
+
+            if !listener.auth && !listener.bind.ip().is_loopback() {
+                warn!(
+                    "Listener \"{}\" doesn't require authentication but is reachable from other machines",
+                    listener.name
+                );
+            }
+
+            let proxy = Proxy::builder()
+                .with_addr(listener.bind)
+                .with_rustls_client()
+                .with_ca(ca.clone())
+                .with_http_handler(ProxyHandler::new(
+                    self.client_storage.clone(),
+                    self.session_storage.clone(),
+                    self.failure_tracker.clone(),
+                    self.settings.clone(),
+                    Arc::new(listener.clone()),
+                    self.in_flight.clone(),
+                ))
+                .build();
+
+            let name = listener.name.clone();
+            let mut shutdown = shutdown_rx.clone();
+            let stopped = stopped_tx.clone();
+
+            tokio::spawn(async move {
+                let served = proxy
+                    .start(async move {
+                        let _ = shutdown.changed().await;
+                    })
+                    .await;
+
+                let _ = stopped.send((name, served));
+            });
+        }
+
+        drop(stopped_tx);
+
+        let served = drain(stopped_rx, shutdown_tx, &self.in_flight, self.drain_timeout).await;
+
+        for sweeper in sweepers {
+            sweeper.abort();
+        }
+
+        let storages: [(&str, &dyn Sweep); 3] = [
+            ("client", self.client_storage.as_ref()),
+            ("session", self.session_storage.as_ref()),
+            ("lockout", self.failure_tracker.as_ref()),
+        ];
+
+        for (name, storage) in storages {
+            let metrics = storage.metrics();
+
+            info!(
+                "The {name} storage holds {} entries, {} evicted in total",
+                metrics.entries, metrics.evicted
+            );
+        }
+
+        reloads.abort();
+        self.reloader.stop_health_checks();
+
+        if let Some(snapshots) = snapshots {
+            snapshots.abort();
+        }
+
+        // Saved even if the drain didn't finish, so the sessions survive the restart
+        let flushed = match &self.state_file {
+            Some(state_file) => state_file
+                .save(&self.session_storage, &self.client_storage)
+                .change_context(ShutdownError::Flush),
+            None => Ok(()),
+        };
+
+        match (served, flushed) {
+            (Err(served), Err(flushed)) => {
+                error!("{flushed:?}");
+                Err(served)
+            }
+            (served, flushed) => served.and(flushed),
         }
     }
+}
+
+type Stopped = (String, std::result::Result<(), hudsucker::Error>);
+
+/// Serves until a shutdown signal arrives or a listener stops on its own, then
+/// stops every listener and waits for them and the in-flight requests, up to
+/// the drain timeout. A second signal skips the rest of the drain
+async fn drain(
+    mut stopped: mpsc::UnboundedReceiver<Stopped>,
+    shutdown: watch::Sender<bool>,
+    in_flight: &InFlight,
+    drain_timeout: Duration,
+) -> Result<(), ShutdownError> {
+    let mut served = tokio::select! {
+        signal = shutdown_signal() => {
+            info!("Received {signal}, draining in-flight requests for up to {drain_timeout:?}");
+            Ok(())
+        }
+        Some((name, result)) = stopped.recv() => {
+            let report = match result {
+                Ok(()) => Report::new(ShutdownError::Server),
+                Err(err) => Report::new(err).change_context(ShutdownError::Server),
+            };
 
-    pub async fn start(&self, ca: RcgenAuthority) {
-        let proxy = Proxy::builder()
-            .with_addr(self.bind_addr)
-            .with_rustls_client()
-            .with_ca(ca)
-            .with_http_handler(ProxyHandler::new(
-                self.client_storage.clone(),
-                self.session_storage.clone(),
-            ))
-            .build();
+            Err(report.attach_printable(format!("Listener: {name}")))
+        }
+    };
+
+    let _ = shutdown.send(true);
 
-        if let Err(e) = proxy.start(shutdown_signal()).await {
-            error!("{}", e);
+    let remaining = async {
+        while let Some((name, result)) = stopped.recv().await {
+            if let Err(err) = result {
+                error!("Listener \"{name}\" failed while draining: {err}");
+            }
         }
+
+        // Tunnelled requests outlive the listeners that accepted them
+        in_flight.wait_idle().await;
+    };
+
+    let drained = tokio::select! {
+        drained = tokio::time::timeout(drain_timeout, remaining) => drained.is_ok(),
+        signal = shutdown_signal() => {
+            warn!("Received {signal} again, shutting down without draining");
+            false
+        }
+    };
+
+    if !drained && served.is_ok() {
+        served = Err(Report::new(ShutdownError::DrainTimeout)
+            .attach_printable(format!("Drain timeout: {drain_timeout:?}"))
+            .attach_printable(format!("Requests in flight: {}", in_flight.count())));
     }
+
+    served
 }
 
-async fn shutdown_signal() {
-    tokio::signal::ctrl_c()
-        .await
-        .expect("Failed to install CTRL+C signal handler");
+/// Resolves with the name of the first shutdown signal received. Signals that
+/// can't be listened for are logged and ignored
+async fn shutdown_signal() -> &'static str {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("Could not listen for CTRL+C: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                error!("Could not listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => "SIGINT",
+        _ = terminate => "SIGTERM",
+    }
 }
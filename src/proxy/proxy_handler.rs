@@ -1,39 +1,134 @@
 use std::sync::Arc;
 
-use cached::async_sync::Mutex;
+use error_stack::{Report, Result, ResultExt};
 use hudsucker::{
     async_trait::async_trait,
     hyper::{http::uri::Scheme, Body, Request, Response, Uri},
     HttpContext, HttpHandler, RequestOrResponse,
 };
-use log::{trace, warn};
+use log::{debug, error, trace, warn};
 use reqwest_impersonate::{
     header::{ACCEPT, ACCEPT_ENCODING, HOST},
-    Method,
+    Method, StatusCode,
 };
 
+use super::{
+    in_flight::InFlight,
+    reload::{Settings, SharedSettings},
+    upstream_error::UpstreamError,
+};
 use crate::{
-    auth::handle_auth,
-    convert::response_reqwest_to_hud,
+    auth::{handle_auth, open_session, CreateSessionError, FailureTracker, Session},
+    config::ListenerConfig,
+    convert::{request_hud_to_reqwest, response_reqwest_to_hud, BodySent},
     response,
-    route::get_route_type,
-    storage::{ClientHash, ClientStorage, ConnectionHash, SessionStorage},
+    route::{RouteError, RouteType},
+    storage::{ClientStorage, ConnectionHash, SessionStorage},
+    timeouts::Timeouts,
 };
 
 #[derive(Clone)]
 pub struct ProxyHandler {
-    client_storage: Arc<Mutex<ClientStorage>>,
-    session_storage: Arc<Mutex<SessionStorage>>,
+    client_storage: Arc<ClientStorage>,
+    session_storage: Arc<SessionStorage>,
+    failure_tracker: Arc<FailureTracker>,
+    settings: Arc<SharedSettings>,
+    listener: Arc<ListenerConfig>,
+    in_flight: Arc<InFlight>,
 }
 
 impl ProxyHandler {
     pub fn new(
-        client_storage: Arc<Mutex<ClientStorage>>,
-        session_storage: Arc<Mutex<SessionStorage>>,
+        client_storage: Arc<ClientStorage>,
+        session_storage: Arc<SessionStorage>,
+        failure_tracker: Arc<FailureTracker>,
+        settings: Arc<SharedSettings>,
+        listener: Arc<ListenerConfig>,
+        in_flight: Arc<InFlight>,
     ) -> Self {
         Self {
             client_storage,
             session_storage,
+            failure_tracker,
+            settings,
+            listener,
+            in_flight,
+        }
+    }
+
+    /// Sends the request to the host. Idempotent requests are retried when the
+    /// upstream can't be reached, on another member of the pool if the session
+    /// allows switching exits
+    async fn execute(
+        &self,
+        settings: &Settings,
+        conn_hash: &ConnectionHash,
+        session: &Session,
+        mut route: RouteType,
+        timeouts: Timeouts,
+        mut req: reqwest_impersonate::Request,
+        mut body_sent: BodySent,
+    ) -> Result<reqwest_impersonate::Response, UpstreamError> {
+        let mut tried = Vec::new();
+        let mut attempt = 0;
+
+        loop {
+            // Streamed bodies can't be replayed, requests carrying one are only sent once
+            let retry = if settings.retry_policy.allows_retry(req.method(), attempt) {
+                req.try_clone()
+            } else {
+                None
+            };
+
+            let client_hash = self
+                .client_storage
+                .client_hash(conn_hash, session, &route, &timeouts);
+            let client = self
+                .client_storage
+                .acquire_client(client_hash, session, &route, timeouts)
+                .change_context(UpstreamError::Client)?;
+
+            // The total timeout is enforced by the client, the response head has its
+            // own which only starts once the body was sent, so long uploads aren't cut off
+            let first_byte = async {
+                body_sent.wait().await;
+                tokio::time::sleep(timeouts.first_byte).await;
+            };
+
+            let executed = tokio::select! {
+                executed = client.execute(req) => Some(executed),
+                _ = first_byte => None,
+            };
+
+            let report = match executed {
+                Some(Ok(res)) => return Ok(res),
+                Some(Err(err)) => {
+                    let kind = UpstreamError::classify(&err);
+                    Report::new(err).change_context(kind)
+                }
+                None => Report::new(UpstreamError::Timeout)
+                    .attach_printable(format!("No response within {:?}", timeouts.first_byte)),
+            }
+            .attach_printable(format!("Route: {}", route.id()));
+
+            req = match retry {
+                Some(retry) if report.current_context().is_retryable() => retry,
+                _ => return Err(report),
+            };
+
+            debug!("Retrying a failed request\n{report:?}");
+
+            tried.push(route.id());
+
+            if session.allows_failover() {
+                if let Some(other) = settings.router.failover(session, &tried) {
+                    debug!("Failing over from {} to {}", route.id(), other.id());
+                    route = other;
+                }
+            }
+
+            tokio::time::sleep(settings.retry_policy.backoff(attempt)).await;
+            attempt += 1;
         }
     }
 }
@@ -44,14 +139,35 @@ impl HttpHandler for ProxyHandler {
         trace!("Processing incoming request");
 
         let conn_hash = ConnectionHash::new(ctx, &req);
+        // The whole request uses the same settings, even if they are reloaded meanwhile
+        let settings = self.settings.current();
 
         if req.method() == Method::CONNECT {
-            match handle_auth(ctx, &req) {
+            let defaults = self.listener.session_defaults(&settings.session_defaults);
+
+            let auth = if self.listener.auth {
+                handle_auth(
+                    ctx,
+                    &req,
+                    settings.authenticator.as_ref(),
+                    self.failure_tracker.as_ref(),
+                    &defaults,
+                )
+                .await
+            } else {
+                open_session(ctx, &req, &self.listener.name, &defaults)
+            };
+
+            match auth {
                 Ok(session) => {
-                    self.session_storage
-                        .lock()
-                        .await
-                        .insert_session(conn_hash, session);
+                    // Reject sessions that can't be routed before opening the tunnel
+                    if let Err(err) = settings.router.route(&session) {
+                        debug!("Could not route session\n{err:?}");
+
+                        return RequestOrResponse::Response(route_error_response(&err));
+                    }
+
+                    self.session_storage.insert_session(conn_hash, session);
 
                     trace!("CONNECT successful");
 
@@ -60,34 +176,78 @@ impl HttpHandler for ProxyHandler {
                 }
 
                 Err(err) => {
-                    warn!("Proxy connect auth failed\n{err:?}");
+                    // The failure itself is already recorded as an audit event
+                    debug!("Proxy connect auth failed\n{err:?}");
 
-                    RequestOrResponse::Response(response::auth_needed())
+                    match err.current_context() {
+                        CreateSessionError::LockedOut { retry_after, .. } => {
+                            RequestOrResponse::Response(response::too_many_requests(*retry_after))
+                        }
+                        _ => RequestOrResponse::Response(response::auth_needed()),
+                    }
                 }
             }
-        } else if let Some(session) = self.session_storage.lock().await.get_session(&conn_hash) {
-            let route_type = get_route_type(session);
-            let client_hash = ClientHash::new(&conn_hash, session, &route_type);
+        } else if let Some(session) = self.session_storage.get_session(&conn_hash) {
+            // Released once the response body is done, shutdown waits for it
+            let in_flight = self.in_flight.start();
+
+            let route = match settings.router.route(&session) {
+                Ok(route) => route,
+                Err(err) => {
+                    debug!("Could not route session\n{err:?}");
+
+                    return RequestOrResponse::Response(route_error_response(&err));
+                }
+            };
+            let timeouts = session.timeouts(&settings.router.timeouts(&session));
 
-            let mut reqwest_req: reqwest_impersonate::Request = req.try_into().unwrap();
+            let (mut reqwest_req, body_sent) = match request_hud_to_reqwest(req) {
+                Ok(converted) => converted,
+                Err(err) => {
+                    debug!("Could not convert the incoming request\n{err:?}");
+
+                    return RequestOrResponse::Response(response::error(
+                        StatusCode::BAD_REQUEST,
+                        "Malformed request",
+                    ));
+                }
+            };
 
             // Remove redundant headers to keep the fingerprint in check
             reqwest_req.headers_mut().remove(HOST);
             reqwest_req.headers_mut().remove(ACCEPT);
             reqwest_req.headers_mut().remove(ACCEPT_ENCODING);
 
-            let mut storage = self.client_storage.lock().await;
-
-            let client = storage.acquire_client(client_hash, session);
-
-            match client.execute(reqwest_req).await {
+            match self
+                .execute(
+                    &settings,
+                    &conn_hash,
+                    &session,
+                    route,
+                    timeouts,
+                    reqwest_req,
+                    body_sent,
+                )
+                .await
+            {
                 Ok(res) => {
-                    let http_res = response_reqwest_to_hud(res).await.unwrap();
+                    // Only the head is converted here, the body keeps streaming in the background
+                    match response_reqwest_to_hud(res, in_flight) {
+                        Ok(http_res) => RequestOrResponse::Response(http_res),
+                        Err(err) => {
+                            error!("Could not convert the upstream response\n{err:?}");
 
-                    RequestOrResponse::Response(http_res)
+                            RequestOrResponse::Response(response::internal_server_error())
+                        }
+                    }
                 }
-                Err(_) => {
-                    return RequestOrResponse::Response(response::internal_server_error());
+                Err(report) => {
+                    match report.current_context() {
+                        UpstreamError::Client => error!("Could not acquire a client\n{report:?}"),
+                        _ => warn!("Request to the host failed\n{report:?}"),
+                    }
+
+                    RequestOrResponse::Response(upstream_error_response(report.current_context()))
                 }
             }
         } else {
@@ -118,3 +278,21 @@ impl HttpHandler for ProxyHandler {
         res
     }
 }
+
+fn upstream_error_response(kind: &UpstreamError) -> Response<Body> {
+    response::upstream_error(kind.status(), kind.code(), &kind.to_string())
+}
+
+fn route_error_response(err: &Report<RouteError>) -> Response<Body> {
+    match err.current_context() {
+        RouteError::UnsupportedCountry(_) => {
+            response::error(StatusCode::BAD_REQUEST, &err.current_context().to_string())
+        }
+        // The configuration is validated against the routes, so this is a bug
+        RouteError::UnknownRoute(_) => {
+            error!("{err:?}");
+
+            response::internal_server_error()
+        }
+    }
+}
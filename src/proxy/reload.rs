@@ -0,0 +1,243 @@
+//! Swaps in new authentication, routing and session settings while the proxy
+//! runs, on SIGHUP or when one of the configuration files changes. Requests
+//! keep the snapshot they started with, so in-flight requests finish on the
+//! old settings and sticky sessions survive the reload
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::SystemTime,
+};
+
+use error_stack::{bail, Report, Result, ResultExt};
+use log::{error, info};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+use super::RetryPolicy;
+use crate::{
+    auth::{authenticator_from_config, Authenticator, SessionDefaults},
+    config::{Config, ConfigSource, ListenerConfig, ReloadConfig},
+    route::Router,
+};
+
+#[derive(Debug, Error)]
+pub enum ReloadError {
+    #[error("Could not load the configuration")]
+    Config,
+    #[error("Could not set up the authentication backend")]
+    Auth,
+    #[error("Could not load the routes")]
+    Routes,
+}
+
+/// The part of the configuration that can change without a restart
+pub struct Settings {
+    pub authenticator: Arc<dyn Authenticator>,
+    pub router: Arc<Router>,
+    pub session_defaults: SessionDefaults,
+    pub retry_policy: RetryPolicy,
+}
+
+impl Settings {
+    /// Loads the settings, checking that the routes of the `listeners` exist
+    pub fn from_config(config: &Config, listeners: &[ListenerConfig]) -> Result<Self, ReloadError> {
+        let authenticator =
+            authenticator_from_config(&config.auth).change_context(ReloadError::Auth)?;
+
+        let router = Router::from_config(&config.routes, config.health.clone())
+            .change_context(ReloadError::Routes)?;
+
+        for listener in listeners {
+            if let Some(route) = listener
+                .route
+                .as_ref()
+                .filter(|route| !router.has_route(route))
+            {
+                bail!(Report::new(ReloadError::Routes).attach_printable(format!(
+                    "Listener \"{}\" uses the unknown route \"{route}\"",
+                    listener.name
+                )));
+            }
+        }
+
+        Ok(Self {
+            authenticator,
+            router: Arc::new(router),
+            session_defaults: config.session_defaults(),
+            retry_policy: config.retry.clone(),
+        })
+    }
+}
+
+/// The settings currently in use, replaced as a whole on reload
+pub struct SharedSettings {
+    current: RwLock<Arc<Settings>>,
+}
+
+impl SharedSettings {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(settings)),
+        }
+    }
+
+    /// A snapshot of the settings, unaffected by later reloads
+    pub fn current(&self) -> Arc<Settings> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn replace(&self, settings: Settings) {
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(settings);
+    }
+}
+
+pub struct Reloader {
+    source: ConfigSource,
+    /// The listeners that are running, their routes must survive a reload as
+    /// listeners only change on restart
+    listeners: Vec<ListenerConfig>,
+    settings: Arc<SharedSettings>,
+    watched: Mutex<Vec<PathBuf>>,
+    /// Health checks of the current router, restarted along with it
+    health_checks: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Reloader {
+    pub fn new(
+        source: ConfigSource,
+        config: &Config,
+        listeners: Vec<ListenerConfig>,
+        settings: Arc<SharedSettings>,
+    ) -> Self {
+        Self {
+            watched: Mutex::new(config.watched_files(&source)),
+            source,
+            listeners,
+            settings,
+            health_checks: Mutex::new(None),
+        }
+    }
+
+    /// Loads the configuration again and swaps in the new settings, leaving
+    /// the current ones untouched if anything is wrong
+    pub fn reload(&self) -> Result<(), ReloadError> {
+        let config = self.source.load().change_context(ReloadError::Config)?;
+        let settings = Settings::from_config(&config, &self.listeners)?;
+
+        *lock(&self.watched) = config.watched_files(&self.source);
+
+        self.settings.replace(settings);
+        self.restart_health_checks();
+
+        info!(
+            "Reloaded the users, routes and session settings, other changes apply after a restart"
+        );
+
+        Ok(())
+    }
+
+    fn restart_health_checks(&self) {
+        let mut health_checks = lock(&self.health_checks);
+
+        if let Some(previous) = health_checks.take() {
+            previous.abort();
+        }
+
+        *health_checks = self.settings.current().router.spawn_health_checks();
+    }
+
+    /// Starts the health checks and reloads on SIGHUP, and whenever a watched
+    /// file changes if `watch` is set
+    pub fn spawn(self: Arc<Self>, config: &ReloadConfig) -> JoinHandle<()> {
+        self.restart_health_checks();
+
+        let watch = config.watch;
+        let mut ticker = tokio::time::interval(config.interval);
+
+        tokio::spawn(async move {
+            let mut hangups = hangup_signal();
+            let mut modified = self.modified();
+
+            loop {
+                tokio::select! {
+                    _ = hangups.recv() => info!("Received SIGHUP, reloading the configuration"),
+                    _ = ticker.tick(), if watch => {
+                        let current = self.modified();
+
+                        if current == modified {
+                            continue;
+                        }
+
+                        info!("A configuration file changed, reloading the configuration");
+                    }
+                }
+
+                if let Err(err) = self.reload() {
+                    error!("Reload rejected, keeping the current configuration\n{err:?}");
+                }
+
+                // A rejected file isn't retried until it changes again
+                modified = self.modified();
+            }
+        })
+    }
+
+    /// Stops the health checks of the current router
+    pub fn stop_health_checks(&self) {
+        if let Some(health_checks) = lock(&self.health_checks).take() {
+            health_checks.abort();
+        }
+    }
+
+    /// When each watched file was last modified, `None` if it can't be read
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        lock(&self.watched)
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// SIGHUP only exists on unix, elsewhere reloads are only triggered by file
+/// changes. The same goes for when the handler can't be installed
+struct HangupSignal {
+    #[cfg(unix)]
+    inner: Option<tokio::signal::unix::Signal>,
+}
+
+fn hangup_signal() -> HangupSignal {
+    HangupSignal {
+        #[cfg(unix)]
+        inner: match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(signal) => Some(signal),
+            Err(err) => {
+                error!("Could not listen for SIGHUP, only file changes trigger reloads: {err}");
+                None
+            }
+        },
+    }
+}
+
+impl HangupSignal {
+    #[cfg(unix)]
+    async fn recv(&mut self) -> Option<()> {
+        match &mut self.inner {
+            Some(signal) => signal.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) -> Option<()> {
+        std::future::pending().await
+    }
+}
@@ -0,0 +1,58 @@
+//! Retries of requests whose upstream could not be reached
+
+use std::time::Duration;
+
+use error_stack::Result;
+use reqwest_impersonate::Method;
+use serde::Deserialize;
+
+use crate::config::{duration_str, vars, ConfigError};
+
+const MAX_RETRIES: &str = "HUD_MAX_RETRIES";
+const RETRY_BACKOFF_MS: &str = "HUD_RETRY_BACKOFF_MS";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Attempts made on top of the first one
+    pub max_retries: u32,
+    /// Wait before the first retry, growing linearly with every other one
+    #[serde(with = "duration_str")]
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Overrides the policy with `HUD_MAX_RETRIES` and `HUD_RETRY_BACKOFF_MS`
+    pub fn with_env(self) -> Result<Self, ConfigError> {
+        Ok(Self {
+            max_retries: vars::parse(MAX_RETRIES)?.unwrap_or(self.max_retries),
+            backoff: vars::millis(RETRY_BACKOFF_MS)?.unwrap_or(self.backoff),
+        })
+    }
+
+    /// Whether another attempt may follow the given one, counting from zero
+    pub fn allows_retry(&self, method: &Method, attempt: u32) -> bool {
+        attempt < self.max_retries && is_idempotent(method)
+    }
+
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(attempt + 1)
+    }
+}
+
+/// Sending these more than once has the same effect as sending them once
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
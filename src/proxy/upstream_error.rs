@@ -0,0 +1,113 @@
+//! Sorts the errors of requests to the upstream into something a proxy
+//! client can act upon
+
+use std::error::Error as StdError;
+
+use reqwest_impersonate::StatusCode;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum UpstreamError {
+    #[error("Could not resolve the host")]
+    Dns,
+    #[error("Could not connect to the host")]
+    Connect,
+    #[error("The upstream proxy refused to open a tunnel")]
+    Tunnel,
+    #[error("The TLS handshake with the host failed")]
+    Tls,
+    #[error("The host took too long to respond")]
+    Timeout,
+    #[error("The host redirected too many times")]
+    Redirect,
+    #[error("The request body could not be read")]
+    RequestBody,
+    #[error("The host sent an invalid response")]
+    Protocol,
+    #[error("The request to the host failed")]
+    Other,
+    #[error("Could not set up a client for the route")]
+    Client,
+}
+
+impl UpstreamError {
+    /// reqwest only tells connect errors apart, the finer grained causes are
+    /// taken from the messages of the underlying errors
+    pub fn classify(err: &reqwest_impersonate::Error) -> Self {
+        if err.is_timeout() {
+            return Self::Timeout;
+        }
+
+        if err.is_redirect() {
+            return Self::Redirect;
+        }
+
+        if err.is_body() {
+            return Self::RequestBody;
+        }
+
+        let chain = error_chain(err);
+
+        if chain.contains("dns error") || chain.contains("failed to lookup address") {
+            Self::Dns
+        } else if chain.contains("tunnel") || chain.contains("socks") {
+            Self::Tunnel
+        } else if ["ssl", "tls", "certificate", "handshake"]
+            .iter()
+            .any(|needle| chain.contains(needle))
+        {
+            Self::Tls
+        } else if err.is_connect() {
+            Self::Connect
+        } else if err.is_request() || err.is_decode() {
+            Self::Protocol
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Failures that happen before the host saw the request, so another
+    /// attempt can't cause it to be handled twice
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Connect | Self::Tunnel | Self::Tls)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::RequestBody => StatusCode::BAD_REQUEST,
+            Self::Client => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Short identifier sent in the `X-Hud-Error` header
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::Connect => "connect",
+            Self::Tunnel => "tunnel",
+            Self::Tls => "tls",
+            Self::Timeout => "timeout",
+            Self::Redirect => "redirect",
+            Self::RequestBody => "request-body",
+            Self::Protocol => "protocol",
+            Self::Other => "upstream",
+            Self::Client => "client",
+        }
+    }
+}
+
+/// The lowercased messages of the error and all of its sources
+fn error_chain(err: &reqwest_impersonate::Error) -> String {
+    let mut chain = err.to_string();
+    let mut source = err.source();
+
+    while let Some(err) = source {
+        chain.push_str(": ");
+        chain.push_str(&err.to_string());
+        source = err.source();
+    }
+
+    chain.to_ascii_lowercase()
+}
@@ -1,9 +1,15 @@
+use std::time::Duration;
+
 use hudsucker::hyper::{Body, Response, Uri};
 use reqwest_impersonate::{
-    header::{LOCATION, PROXY_AUTHENTICATE},
+    header::{CONTENT_TYPE, LOCATION, PROXY_AUTHENTICATE, RETRY_AFTER},
     StatusCode,
 };
 
+/// Header telling proxy clients why their request failed, to set the errors of
+/// the proxy apart from the ones of the host
+const HUD_ERROR: &str = "x-hud-error";
+
 /// Shorthand to create an auth required response
 pub fn auth_needed() -> Response<Body> {
     Response::builder()
@@ -13,6 +19,18 @@ pub fn auth_needed() -> Response<Body> {
         .unwrap()
 }
 
+/// Shorthand to create a response for clients that are temporarily locked out
+pub fn too_many_requests(retry_after: Duration) -> Response<Body> {
+    // Round up so clients never retry before the lock is lifted
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(RETRY_AFTER, secs.to_string())
+        .body(Body::empty())
+        .unwrap()
+}
+
 /// Shorthand to create a permanent redirect response
 pub fn permanent_redirect(req: &Uri) -> Response<Body> {
     Response::builder()
@@ -22,6 +40,26 @@ pub fn permanent_redirect(req: &Uri) -> Response<Body> {
         .unwrap()
 }
 
+/// Shorthand to create an error response with a short plaintext explanation
+pub fn error(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
+
+/// Shorthand to create an error response for a failed request to the host,
+/// `code` ends up in the `X-Hud-Error` header
+pub fn upstream_error(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(HUD_ERROR, code)
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
+
 /// Shorthand to create an internal server error response
 pub fn internal_server_error() -> Response<Body> {
     Response::builder()
@@ -0,0 +1,251 @@
+//! On-disk definition of the available routes
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{bail, IntoReport, Report, Result, ResultExt};
+use reqwest_impersonate::Url;
+use serde::Deserialize;
+use thiserror::Error;
+
+use super::upstream::{placeholders, PLACEHOLDERS};
+use crate::{
+    auth::is_country_code,
+    config::{vars, ConfigError},
+    timeouts::TimeoutOverrides,
+};
+
+/// Path to a routes file, replacing the routes of the configuration file
+const ROUTES_FILE: &str = "HUD_ROUTES_FILE";
+
+/// A single entry in the `routes` table. Upstream credentials may contain
+/// session placeholders such as `{session_id}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RouteDefinition {
+    Direct,
+    Http {
+        url: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    Socks5 {
+        url: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    Pool {
+        members: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RoutesConfig {
+    /// Route used when nothing more specific matches, direct if omitted
+    pub default: Option<String>,
+    #[serde(default)]
+    pub routes: HashMap<String, RouteDefinition>,
+    /// Per-customer route overrides
+    #[serde(default)]
+    pub customers: HashMap<String, String>,
+    /// Routes for the ISO 3166-1 alpha-2 codes passed in the `country`
+    /// parameter. Sessions asking for a country missing here are rejected
+    #[serde(default)]
+    pub countries: HashMap<String, String>,
+    /// Per-route timeouts, pools apply theirs to every member
+    #[serde(default)]
+    pub timeouts: HashMap<String, TimeoutOverrides>,
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid route configuration")]
+pub struct RoutesConfigError;
+
+/// The `[routes]` section of the configuration, holding either the path to a
+/// routes file or the routes themselves
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RoutesSection {
+    pub file: Option<PathBuf>,
+    #[serde(flatten)]
+    pub inline: RoutesConfig,
+}
+
+impl RoutesSection {
+    /// A routes file, e.g. from `HUD_ROUTES_FILE`, replacing the configured
+    /// routes
+    pub fn from_file(path: PathBuf) -> Self {
+        Self {
+            file: Some(path),
+            inline: RoutesConfig::default(),
+        }
+    }
+
+    pub fn with_env(self) -> Result<Self, ConfigError> {
+        Ok(vars::parse(ROUTES_FILE)?.map_or(self, Self::from_file))
+    }
+
+    /// The validated routes, [`None`] if there are none
+    pub fn load(&self) -> Result<Option<RoutesConfig>, RoutesConfigError> {
+        match &self.file {
+            Some(path) if self.inline.is_empty() => RoutesConfig::from_path(path).map(Some),
+            Some(path) => bail!(Report::new(RoutesConfigError).attach_printable(format!(
+                "Routes are listed inline and in {}, pick one",
+                path.display()
+            ))),
+            None if self.inline.is_empty() => Ok(None),
+            None => {
+                self.inline.validate()?;
+
+                Ok(Some(self.inline.clone()))
+            }
+        }
+    }
+}
+
+impl RoutesConfig {
+    pub fn from_path(path: &Path) -> Result<Self, RoutesConfigError> {
+        let contents = fs::read_to_string(path)
+            .into_report()
+            .attach_printable_lazy(|| format!("Path: {}", path.display()))
+            .change_context(RoutesConfigError)?;
+
+        let config: Self = toml::from_str(&contents)
+            .into_report()
+            .change_context(RoutesConfigError)?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.default.is_none()
+            && self.routes.is_empty()
+            && self.customers.is_empty()
+            && self.countries.is_empty()
+            && self.timeouts.is_empty()
+    }
+
+    /// Checks that every referenced route exists and that upstream URLs are
+    /// usable
+    pub fn validate(&self) -> Result<(), RoutesConfigError> {
+        for (name, route) in &self.routes {
+            match route {
+                RouteDefinition::Direct => {}
+                RouteDefinition::Http {
+                    url,
+                    username,
+                    password,
+                } => {
+                    check_url(name, url, &["http", "https"])?;
+                    check_credentials(name, username, password)?;
+                }
+                RouteDefinition::Socks5 {
+                    url,
+                    username,
+                    password,
+                } => {
+                    check_url(name, url, &["socks5", "socks5h"])?;
+                    check_credentials(name, username, password)?;
+                }
+                RouteDefinition::Pool { members } => {
+                    if members.is_empty() {
+                        bail!(Report::new(RoutesConfigError)
+                            .attach_printable(format!("Pool \"{name}\" has no members")));
+                    }
+
+                    for member in members {
+                        match self.routes.get(member) {
+                            Some(RouteDefinition::Pool { .. }) => {
+                                bail!(Report::new(RoutesConfigError).attach_printable(format!(
+                                    "Pool \"{name}\" contains another pool \"{member}\""
+                                )))
+                            }
+                            Some(_) => {}
+                            None => bail!(Report::new(RoutesConfigError).attach_printable(
+                                format!("Pool \"{name}\" references unknown route \"{member}\"")
+                            )),
+                        }
+                    }
+                }
+            }
+        }
+
+        for country in self.countries.keys() {
+            if !is_country_code(&country.to_ascii_uppercase()) {
+                bail!(Report::new(RoutesConfigError)
+                    .attach_printable(format!("\"{country}\" is not an ISO 3166-1 country code")));
+            }
+        }
+
+        let references = self.default.iter().map(|route| ("default", route)).chain(
+            self.customers
+                .iter()
+                .chain(&self.countries)
+                .map(|(owner, route)| (owner.as_str(), route)),
+        );
+
+        for route in self.timeouts.keys() {
+            if !self.routes.contains_key(route) {
+                bail!(Report::new(RoutesConfigError)
+                    .attach_printable(format!("Timeouts set for unknown route \"{route}\"")));
+            }
+        }
+
+        for (owner, route) in references {
+            if !self.routes.contains_key(route) {
+                bail!(Report::new(RoutesConfigError)
+                    .attach_printable(format!("\"{owner}\" references unknown route \"{route}\"")));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn check_url(name: &str, url: &str, schemes: &[&str]) -> Result<(), RoutesConfigError> {
+    let parsed = Url::parse(url)
+        .into_report()
+        .attach_printable_lazy(|| format!("Route \"{name}\" has an invalid url"))
+        .change_context(RoutesConfigError)?;
+
+    if parsed.host_str().is_none() {
+        bail!(Report::new(RoutesConfigError)
+            .attach_printable(format!("Route \"{name}\" has no host in its url")));
+    }
+
+    if !schemes.contains(&parsed.scheme()) {
+        bail!(Report::new(RoutesConfigError).attach_printable(format!(
+            "Route \"{name}\" uses the scheme \"{}\", expected one of {}",
+            parsed.scheme(),
+            schemes.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+fn check_credentials(
+    name: &str,
+    username: &Option<String>,
+    password: &Option<String>,
+) -> Result<(), RoutesConfigError> {
+    if password.is_some() && username.is_none() {
+        bail!(Report::new(RoutesConfigError)
+            .attach_printable(format!("Route \"{name}\" has a password but no username")));
+    }
+
+    for template in username.iter().chain(password) {
+        if let Some(unknown) = placeholders(template).find(|p| !PLACEHOLDERS.contains(p)) {
+            bail!(Report::new(RoutesConfigError).attach_printable(format!(
+                "Route \"{name}\" uses the unknown placeholder {{{unknown}}}, expected one of {}",
+                PLACEHOLDERS.join(", ")
+            )));
+        }
+    }
+
+    Ok(())
+}
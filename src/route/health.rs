@@ -0,0 +1,422 @@
+//! Active health checks of the upstreams in pools. Every member is probed
+//! periodically and guarded by a circuit breaker: enough consecutive failures
+//! open it, taking the member out of the pool, and once it has cooled down a
+//! single probe decides whether it closes again
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use error_stack::Result;
+use log::{debug, info, warn};
+use reqwest_impersonate::{Client, Url};
+use serde::{de, Deserialize, Deserializer};
+use tokio::task::JoinHandle;
+
+use super::RouteType;
+use crate::config::{duration_str, vars, ConfigError};
+
+/// Url requested through every upstream, setting it enables the health checks
+const HEALTH_CHECK_URL: &str = "HUD_HEALTH_CHECK_URL";
+const HEALTH_CHECK_INTERVAL_SECS: &str = "HUD_HEALTH_CHECK_INTERVAL_SECS";
+const HEALTH_CHECK_TIMEOUT_SECS: &str = "HUD_HEALTH_CHECK_TIMEOUT_SECS";
+const HEALTH_FAILURE_THRESHOLD: &str = "HUD_HEALTH_FAILURE_THRESHOLD";
+const HEALTH_OPEN_SECS: &str = "HUD_HEALTH_OPEN_SECS";
+const HEALTH_CHECK_USERNAME: &str = "HUD_HEALTH_CHECK_USERNAME";
+const HEALTH_CHECK_PASSWORD: &str = "HUD_HEALTH_CHECK_PASSWORD";
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+const DEFAULT_OPEN_DURATION: Duration = Duration::from_secs(60);
+
+/// Weight of the newest probe in the latency and error rate averages
+const EWMA_WEIGHT: f64 = 0.2;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    #[serde(rename = "url", deserialize_with = "deserialize_url")]
+    pub probe_url: Url,
+    #[serde(default = "default_interval", with = "duration_str")]
+    pub interval: Duration,
+    #[serde(default = "default_timeout", with = "duration_str")]
+    pub timeout: Duration,
+    /// Consecutive failed probes that open the circuit
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How long an open circuit waits before probing again
+    #[serde(default = "default_open_duration", with = "duration_str")]
+    pub open_duration: Duration,
+    /// Used by probes in place of templated upstream credentials, as probes
+    /// have no session to fill them in. Upstreams with templated credentials
+    /// aren't probed without them
+    #[serde(rename = "username")]
+    pub probe_username: Option<String>,
+    #[serde(rename = "password")]
+    pub probe_password: Option<String>,
+}
+
+impl HealthConfig {
+    pub fn new(probe_url: Url) -> Self {
+        Self {
+            probe_url,
+            interval: DEFAULT_INTERVAL,
+            timeout: DEFAULT_TIMEOUT,
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            open_duration: DEFAULT_OPEN_DURATION,
+            probe_username: None,
+            probe_password: None,
+        }
+    }
+
+    /// Overrides the configured health checks with the `HUD_HEALTH_*`
+    /// environment variables, setting `HUD_HEALTH_CHECK_URL` enables them
+    pub fn with_env(config: Option<Self>) -> Result<Option<Self>, ConfigError> {
+        let config = match (config, vars::parse(HEALTH_CHECK_URL)?) {
+            (Some(config), Some(probe_url)) => Self {
+                probe_url,
+                ..config
+            },
+            (Some(config), None) => config,
+            (None, Some(probe_url)) => Self::new(probe_url),
+            (None, None) => return Ok(None),
+        };
+
+        let failure_threshold = vars::parse_with(HEALTH_FAILURE_THRESHOLD, |value| {
+            value.parse::<u32>().ok().filter(|threshold| *threshold > 0)
+        })?;
+
+        Ok(Some(Self {
+            interval: vars::secs(HEALTH_CHECK_INTERVAL_SECS)?.unwrap_or(config.interval),
+            timeout: vars::secs(HEALTH_CHECK_TIMEOUT_SECS)?.unwrap_or(config.timeout),
+            failure_threshold: failure_threshold.unwrap_or(config.failure_threshold),
+            open_duration: vars::secs(HEALTH_OPEN_SECS)?.unwrap_or(config.open_duration),
+            probe_username: vars::string(HEALTH_CHECK_USERNAME).or(config.probe_username),
+            probe_password: vars::string(HEALTH_CHECK_PASSWORD).or(config.probe_password),
+        }))
+    }
+}
+
+fn default_interval() -> Duration {
+    DEFAULT_INTERVAL
+}
+
+fn default_timeout() -> Duration {
+    DEFAULT_TIMEOUT
+}
+
+fn default_failure_threshold() -> u32 {
+    DEFAULT_FAILURE_THRESHOLD
+}
+
+fn default_open_duration() -> Duration {
+    DEFAULT_OPEN_DURATION
+}
+
+fn deserialize_url<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Url, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+
+    Url::parse(&raw)
+        .map_err(|err| de::Error::custom(format!("\"{raw}\" is not a valid url: {err}")))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    /// Healthy, part of the pool
+    Closed,
+    /// Unhealthy, left out of the pool until the time is up
+    Open { until: Instant },
+    /// Cooled down, the next probe decides the state
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct MemberHealth {
+    state: BreakerState,
+    consecutive_failures: u32,
+    /// Average probe latency
+    latency: Option<Duration>,
+    /// Average share of failed probes
+    error_rate: f64,
+}
+
+impl Default for MemberHealth {
+    fn default() -> Self {
+        Self {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            latency: None,
+            error_rate: 0.0,
+        }
+    }
+}
+
+/// Health of every pool member, keyed by route name
+#[derive(Debug, Default)]
+pub struct HealthRegistry {
+    members: HashMap<String, Mutex<MemberHealth>>,
+}
+
+impl HealthRegistry {
+    pub fn new(names: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            members: names
+                .into_iter()
+                .map(|name| (name, Mutex::new(MemberHealth::default())))
+                .collect(),
+        }
+    }
+
+    /// Whether the member may be picked. Members that are not tracked are
+    /// always available
+    pub fn is_available(&self, name: &str) -> bool {
+        self.members
+            .get(name)
+            .map_or(true, |health| lock(health).state == BreakerState::Closed)
+    }
+
+    /// Whether the member is due for a probe, moving open circuits that have
+    /// cooled down to half-open
+    fn should_probe(&self, name: &str) -> bool {
+        let mut health = match self.members.get(name) {
+            Some(health) => lock(health),
+            None => return false,
+        };
+
+        match health.state {
+            BreakerState::Open { until } if Instant::now() < until => false,
+            BreakerState::Open { .. } => {
+                health.state = BreakerState::HalfOpen;
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn record_success(&self, name: &str, latency: Duration) {
+        let mut health = match self.members.get(name) {
+            Some(health) => lock(health),
+            None => return,
+        };
+
+        health.latency = Some(match health.latency {
+            Some(average) => average.mul_f64(1.0 - EWMA_WEIGHT) + latency.mul_f64(EWMA_WEIGHT),
+            None => latency,
+        });
+        health.error_rate *= 1.0 - EWMA_WEIGHT;
+        health.consecutive_failures = 0;
+
+        if health.state != BreakerState::Closed {
+            info!("Upstream \"{name}\" recovered, adding it back to its pools");
+            health.state = BreakerState::Closed;
+        }
+
+        debug!(
+            "Upstream \"{name}\" answered in {latency:?}, average {:?}, error rate {:.2}",
+            health.latency.unwrap_or_default(),
+            health.error_rate
+        );
+    }
+
+    fn record_failure(&self, name: &str, reason: &str, config: &HealthConfig) {
+        let mut health = match self.members.get(name) {
+            Some(health) => lock(health),
+            None => return,
+        };
+
+        health.error_rate = health.error_rate * (1.0 - EWMA_WEIGHT) + EWMA_WEIGHT;
+        health.consecutive_failures += 1;
+
+        let trips = match health.state {
+            BreakerState::HalfOpen => true,
+            BreakerState::Closed => health.consecutive_failures >= config.failure_threshold,
+            BreakerState::Open { .. } => false,
+        };
+
+        if trips {
+            warn!(
+                "Upstream \"{name}\" failed {} probes in a row ({reason}), taking it out of its pools for {:?}",
+                health.consecutive_failures, config.open_duration
+            );
+
+            health.state = BreakerState::Open {
+                until: Instant::now() + config.open_duration,
+            };
+        } else {
+            debug!("Upstream \"{name}\" failed a probe: {reason}");
+        }
+    }
+}
+
+// The state is always left consistent, so poisoning is ignored
+fn lock(health: &Mutex<MemberHealth>) -> MutexGuard<'_, MemberHealth> {
+    health.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Probes every target each interval, in parallel
+pub fn spawn_checker(
+    config: HealthConfig,
+    targets: Vec<(String, RouteType)>,
+    registry: Arc<HealthRegistry>,
+) -> JoinHandle<()> {
+    let probes: Vec<(String, Client)> = targets
+        .into_iter()
+        .filter_map(|(name, route)| {
+            let client = route
+                .configure(Client::builder().timeout(config.timeout))
+                .and_then(|builder| builder.build());
+
+            match client {
+                Ok(client) => Some((name, client)),
+                Err(err) => {
+                    warn!("Could not build a health check client for \"{name}\": {err}");
+                    None
+                }
+            }
+        })
+        .collect();
+
+    info!(
+        "Health checking {} upstreams every {:?}",
+        probes.len(),
+        config.interval
+    );
+
+    let config = Arc::new(config);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(config.interval);
+
+        loop {
+            ticker.tick().await;
+
+            let running: Vec<_> = probes
+                .iter()
+                .filter(|(name, _)| registry.should_probe(name))
+                .map(|(name, client)| {
+                    let (name, client) = (name.clone(), client.clone());
+                    let (config, registry) = (config.clone(), registry.clone());
+
+                    tokio::spawn(async move {
+                        let started = Instant::now();
+
+                        match client.get(config.probe_url.clone()).send().await {
+                            Ok(res)
+                                if res.status().is_success() || res.status().is_redirection() =>
+                            {
+                                registry.record_success(&name, started.elapsed());
+                            }
+                            Ok(res) => registry.record_failure(
+                                &name,
+                                &format!("status {}", res.status()),
+                                &config,
+                            ),
+                            Err(err) => registry.record_failure(&name, &err.to_string(), &config),
+                        }
+                    })
+                })
+                .collect();
+
+            for probe in running {
+                if let Err(err) = probe.await {
+                    warn!("A health check probe failed to run: {err}");
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEMBER: &str = "residential";
+
+    fn setup(open_duration: Duration) -> (HealthRegistry, HealthConfig) {
+        let config = HealthConfig {
+            failure_threshold: 2,
+            open_duration,
+            ..HealthConfig::new(Url::parse("http://example.com").unwrap())
+        };
+
+        (HealthRegistry::new([MEMBER.to_string()]), config)
+    }
+
+    fn state(registry: &HealthRegistry) -> BreakerState {
+        lock(&registry.members[MEMBER]).state
+    }
+
+    #[test]
+    fn untracked_members_are_available() {
+        let (registry, _) = setup(DEFAULT_OPEN_DURATION);
+
+        assert!(registry.is_available("unknown"));
+        assert!(!registry.should_probe("unknown"));
+    }
+
+    #[test]
+    fn opens_after_the_threshold() {
+        let (registry, config) = setup(DEFAULT_OPEN_DURATION);
+
+        registry.record_failure(MEMBER, "timeout", &config);
+        assert!(registry.is_available(MEMBER));
+
+        registry.record_failure(MEMBER, "timeout", &config);
+        assert!(!registry.is_available(MEMBER));
+        assert!(matches!(state(&registry), BreakerState::Open { .. }));
+
+        // Still cooling down
+        assert!(!registry.should_probe(MEMBER));
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let (registry, config) = setup(DEFAULT_OPEN_DURATION);
+
+        registry.record_failure(MEMBER, "timeout", &config);
+        registry.record_success(MEMBER, Duration::from_millis(10));
+        registry.record_failure(MEMBER, "timeout", &config);
+
+        assert_eq!(state(&registry), BreakerState::Closed);
+    }
+
+    #[test]
+    fn half_open_closes_on_success() {
+        let (registry, config) = setup(Duration::ZERO);
+
+        registry.record_failure(MEMBER, "timeout", &config);
+        registry.record_failure(MEMBER, "timeout", &config);
+
+        assert!(registry.should_probe(MEMBER));
+        assert_eq!(state(&registry), BreakerState::HalfOpen);
+        assert!(!registry.is_available(MEMBER));
+
+        registry.record_success(MEMBER, Duration::from_millis(10));
+        assert_eq!(state(&registry), BreakerState::Closed);
+        assert!(registry.is_available(MEMBER));
+    }
+
+    #[test]
+    fn half_open_reopens_on_a_single_failure() {
+        let (registry, config) = setup(Duration::ZERO);
+
+        registry.record_failure(MEMBER, "timeout", &config);
+        registry.record_failure(MEMBER, "timeout", &config);
+        assert!(registry.should_probe(MEMBER));
+
+        let config = HealthConfig {
+            open_duration: DEFAULT_OPEN_DURATION,
+            failure_threshold: 100,
+            ..config
+        };
+        registry.record_failure(MEMBER, "status 502", &config);
+
+        assert!(matches!(state(&registry), BreakerState::Open { .. }));
+        assert!(!registry.should_probe(MEMBER));
+    }
+}
@@ -0,0 +1,483 @@
+//! Decides where the requests of a [`Session`] leave the proxy from
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use error_stack::{bail, Report, Result};
+use log::{info, warn};
+use reqwest_impersonate::{ClientBuilder, Proxy, Url};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+use crate::{auth::Session, timeouts::TimeoutOverrides};
+
+mod config;
+mod health;
+mod upstream;
+
+pub use config::{RouteDefinition, RoutesConfig, RoutesConfigError, RoutesSection};
+pub use health::HealthConfig;
+use health::HealthRegistry;
+pub use upstream::Upstream;
+use upstream::UpstreamTemplate;
+
+/// The concrete route a session was mapped to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RouteType {
+    /// Requests leave straight from this machine
+    Direct,
+    /// Requests go through an upstream HTTP(S) proxy
+    HttpProxy(Upstream),
+    /// Requests go through an upstream SOCKS5 proxy
+    Socks5Proxy(Upstream),
+    /// A member of a named pool was picked
+    Pool {
+        name: String,
+        member: Box<RouteType>,
+    },
+}
+
+impl RouteType {
+    /// Identifier of the route without any password, safe to log
+    pub fn id(&self) -> String {
+        match self {
+            Self::Direct => "direct".to_string(),
+            Self::HttpProxy(upstream) => format!("http:{}", upstream.id()),
+            Self::Socks5Proxy(upstream) => format!("socks5:{}", upstream.id()),
+            Self::Pool { name, member } => format!("pool:{name}:{}", member.id()),
+        }
+    }
+
+    /// Unique identifier of the route including the upstream credentials,
+    /// used to keep clients of different routes apart. Never log it
+    pub fn key(&self) -> String {
+        match self {
+            Self::Direct => "direct".to_string(),
+            Self::HttpProxy(upstream) => format!("http:{}", upstream.key()),
+            Self::Socks5Proxy(upstream) => format!("socks5:{}", upstream.key()),
+            Self::Pool { name, member } => format!("pool:{name}:{}", member.key()),
+        }
+    }
+
+    /// Makes a client builder send its requests through this route
+    pub fn configure(&self, builder: ClientBuilder) -> reqwest_impersonate::Result<ClientBuilder> {
+        match self {
+            Self::Direct => Ok(builder.no_proxy()),
+            Self::HttpProxy(upstream) | Self::Socks5Proxy(upstream) => {
+                Ok(builder.proxy(Proxy::all(upstream.url().clone())?))
+            }
+            Self::Pool { member, .. } => member.configure(builder),
+        }
+    }
+}
+
+/// A single route from the configuration, turned into a [`RouteType`] once the
+/// session it is used for is known
+#[derive(Clone)]
+enum RouteTemplate {
+    Direct,
+    HttpProxy(UpstreamTemplate),
+    Socks5Proxy(UpstreamTemplate),
+}
+
+impl RouteTemplate {
+    fn from_definition(definition: &RouteDefinition) -> Option<Self> {
+        let upstream = |url: &str, username: &Option<String>, password: &Option<String>| {
+            // Urls are checked when validating the configuration
+            Url::parse(url)
+                .ok()
+                .map(|url| UpstreamTemplate::new(url, username.clone(), password.clone()))
+        };
+
+        match definition {
+            RouteDefinition::Direct => Some(Self::Direct),
+            RouteDefinition::Http {
+                url,
+                username,
+                password,
+            } => upstream(url, username, password).map(Self::HttpProxy),
+            RouteDefinition::Socks5 {
+                url,
+                username,
+                password,
+            } => upstream(url, username, password).map(Self::Socks5Proxy),
+            RouteDefinition::Pool { .. } => None,
+        }
+    }
+
+    fn render(&self, session: &Session) -> RouteType {
+        match self {
+            Self::Direct => RouteType::Direct,
+            Self::HttpProxy(upstream) => RouteType::HttpProxy(upstream.render(session)),
+            Self::Socks5Proxy(upstream) => RouteType::Socks5Proxy(upstream.render(session)),
+        }
+    }
+
+    /// The route health checks go through, [`None`] if its credentials are
+    /// templated and no probe credentials are configured
+    fn render_probe(&self, config: &HealthConfig) -> Option<RouteType> {
+        let (username, password) = (
+            config.probe_username.as_deref(),
+            config.probe_password.as_deref(),
+        );
+
+        match self {
+            Self::Direct => Some(RouteType::Direct),
+            Self::HttpProxy(upstream) => upstream
+                .render_probe(username, password)
+                .map(RouteType::HttpProxy),
+            Self::Socks5Proxy(upstream) => upstream
+                .render_probe(username, password)
+                .map(RouteType::Socks5Proxy),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error("No route is available for the country \"{0}\"")]
+    UnsupportedCountry(String),
+    #[error("The route \"{0}\" is not configured")]
+    UnknownRoute(String),
+}
+
+enum RouteEntry {
+    Single(RouteTemplate),
+    Pool(Vec<PoolMember>),
+}
+
+/// A route of a pool, along with its name to track its health
+struct PoolMember {
+    name: String,
+    route: RouteTemplate,
+}
+
+/// Maps sessions to routes based on the routes configuration
+pub struct Router {
+    routes: HashMap<String, RouteEntry>,
+    default: Option<String>,
+    customers: HashMap<String, String>,
+    countries: HashMap<String, String>,
+    timeouts: HashMap<String, TimeoutOverrides>,
+    next_member: AtomicUsize,
+    health: Arc<HealthRegistry>,
+    health_config: Option<HealthConfig>,
+}
+
+impl Router {
+    /// A router that sends everything out directly
+    pub fn direct() -> Self {
+        Self {
+            routes: HashMap::new(),
+            default: None,
+            customers: HashMap::new(),
+            countries: HashMap::new(),
+            timeouts: HashMap::new(),
+            next_member: AtomicUsize::new(0),
+            health: Arc::new(HealthRegistry::default()),
+            health_config: None,
+        }
+    }
+
+    /// Builds a router from the configuration, failing if the default or any
+    /// other route doesn't resolve instead of sending its requests out directly
+    pub fn new(config: RoutesConfig) -> Result<Self, RoutesConfigError> {
+        config.validate()?;
+
+        let mut routes = HashMap::with_capacity(config.routes.len());
+
+        for (name, definition) in &config.routes {
+            let entry = match definition {
+                RouteDefinition::Pool { members } => RouteEntry::Pool(
+                    members
+                        .iter()
+                        .map(|member| -> Result<_, RoutesConfigError> {
+                            Ok(PoolMember {
+                                name: member.clone(),
+                                route: resolve(&config, member)?,
+                            })
+                        })
+                        .collect::<Result<_, _>>()?,
+                ),
+                _ => RouteEntry::Single(resolve(&config, name)?),
+            };
+
+            routes.insert(name.clone(), entry);
+        }
+
+        let health = HealthRegistry::new(pool_member_names(&routes));
+
+        Ok(Self {
+            routes,
+            default: config.default,
+            customers: config.customers,
+            countries: config
+                .countries
+                .into_iter()
+                .map(|(country, route)| (country.to_ascii_uppercase(), route))
+                .collect(),
+            timeouts: config.timeouts,
+            next_member: AtomicUsize::new(0),
+            health: Arc::new(health),
+            health_config: None,
+        })
+    }
+
+    /// Enables the health checks of pool members, see [`Router::spawn_health_checks`]
+    pub fn with_health_checks(mut self, config: Option<HealthConfig>) -> Self {
+        self.health_config = config;
+        self
+    }
+
+    /// Loads the configured routes, every request goes out directly if there
+    /// are none
+    pub fn from_config(
+        routes: &RoutesSection,
+        health: Option<HealthConfig>,
+    ) -> Result<Self, RoutesConfigError> {
+        match routes.load()? {
+            Some(config) => {
+                info!(
+                    "Loaded {} routes and {} country mappings",
+                    config.routes.len(),
+                    config.countries.len()
+                );
+
+                Ok(Self::new(config)?.with_health_checks(health))
+            }
+            None => {
+                info!("No routes configured, all requests will go out directly");
+
+                Ok(Self::direct())
+            }
+        }
+    }
+
+    /// Picks the route for the given session. A requested country always
+    /// takes precedence, followed by the customer override, the route of the
+    /// listener and the default
+    pub fn route(&self, session: &Session) -> Result<RouteType, RouteError> {
+        let name = match self.route_name(session)? {
+            Some(name) => name,
+            None => return Ok(RouteType::Direct),
+        };
+
+        let route = match self.routes.get(name) {
+            None => bail!(Report::new(RouteError::UnknownRoute(name.to_string()))),
+            Some(RouteEntry::Single(route)) => route.render(session),
+            Some(RouteEntry::Pool(members)) => RouteType::Pool {
+                name: name.to_string(),
+                member: Box::new(self.pick_member(members, session).route.render(session)),
+            },
+        };
+
+        Ok(route)
+    }
+
+    /// Another member of the pool the session is routed to, skipping the routes
+    /// that were already tried and preferring healthy members. [`None`] if the
+    /// session isn't routed to a pool or every member was tried
+    pub fn failover(&self, session: &Session, tried: &[String]) -> Option<RouteType> {
+        let name = self.route_name(session).ok()??;
+
+        let members = match self.routes.get(name)? {
+            RouteEntry::Pool(members) => members,
+            RouteEntry::Single(_) => return None,
+        };
+
+        let start = self.next_member.fetch_add(1, Ordering::Relaxed);
+
+        let untried: Vec<(&PoolMember, RouteType)> = (0..members.len())
+            .map(|offset| &members[(start + offset) % members.len()])
+            .map(|member| {
+                let route = RouteType::Pool {
+                    name: name.to_string(),
+                    member: Box::new(member.route.render(session)),
+                };
+
+                (member, route)
+            })
+            .filter(|(_, route)| !tried.contains(&route.id()))
+            .collect();
+
+        let healthy = untried
+            .iter()
+            .position(|(member, _)| self.health.is_available(&member.name));
+
+        untried
+            .into_iter()
+            .nth(healthy.unwrap_or(0))
+            .map(|(_, route)| route)
+    }
+
+    /// Probes every pool member in the background, if health checks are
+    /// enabled. Members failing their probes are left out of their pools
+    /// until they recover
+    pub fn spawn_health_checks(&self) -> Option<JoinHandle<()>> {
+        let config = self.health_config.clone()?;
+
+        let mut seen = HashSet::new();
+        let mut targets: Vec<(String, RouteType)> = Vec::new();
+
+        for entry in self.routes.values() {
+            if let RouteEntry::Pool(members) = entry {
+                for member in members {
+                    if !seen.insert(member.name.as_str()) {
+                        continue;
+                    }
+
+                    match member.route.render_probe(&config) {
+                        Some(route) => targets.push((member.name.clone(), route)),
+                        None => warn!(
+                            "Not health checking \"{}\", its credentials are templated and no probe credentials are configured",
+                            member.name
+                        ),
+                    }
+                }
+            }
+        }
+
+        if targets.is_empty() {
+            return None;
+        }
+
+        Some(health::spawn_checker(config, targets, self.health.clone()))
+    }
+
+    /// The timeouts configured for the route of the given session
+    pub fn timeouts(&self, session: &Session) -> TimeoutOverrides {
+        self.route_name(session)
+            .ok()
+            .flatten()
+            .and_then(|name| self.timeouts.get(name))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn route_name<'a>(&'a self, session: &'a Session) -> Result<Option<&'a str>, RouteError> {
+        match session.country() {
+            Some(country) => self
+                .countries
+                .get(country)
+                .map(|name| Some(name.as_str()))
+                .ok_or_else(|| Report::new(RouteError::UnsupportedCountry(country.to_string()))),
+            None => Ok(self
+                .customers
+                .get(session.customer())
+                .map(String::as_str)
+                .or_else(|| session.default_route())
+                .or(self.default.as_deref())),
+        }
+    }
+
+    /// Whether a route or pool with this name is configured
+    pub fn has_route(&self, name: &str) -> bool {
+        self.routes.contains_key(name)
+    }
+
+    /// Sticky sessions always land on the same member, rotating ones are
+    /// spread round-robin. Unhealthy members are skipped, unless the session
+    /// is sticky and didn't allow failover or no member is healthy
+    fn pick_member<'a>(&self, members: &'a [PoolMember], session: &Session) -> &'a PoolMember {
+        let start = match session.session_id() {
+            Some(session_id) => {
+                let mut hasher = DefaultHasher::new();
+                session.customer().hash(&mut hasher);
+                session_id.hash(&mut hasher);
+
+                hasher.finish() as usize
+            }
+            None => self.next_member.fetch_add(1, Ordering::Relaxed),
+        };
+
+        let picked = &members[start % members.len()];
+
+        if !session.allows_failover() {
+            return picked;
+        }
+
+        (0..members.len())
+            .map(|offset| &members[(start + offset) % members.len()])
+            .find(|member| self.health.is_available(&member.name))
+            .unwrap_or(picked)
+    }
+}
+
+/// The template of a route that isn't a pool
+fn resolve(config: &RoutesConfig, name: &str) -> Result<RouteTemplate, RoutesConfigError> {
+    config
+        .routes
+        .get(name)
+        .and_then(RouteTemplate::from_definition)
+        .ok_or_else(|| {
+            Report::new(RoutesConfigError)
+                .attach_printable(format!("The route \"{name}\" doesn't resolve to an egress"))
+        })
+}
+
+/// Names of the routes used as pool members
+fn pool_member_names(routes: &HashMap<String, RouteEntry>) -> Vec<String> {
+    routes
+        .values()
+        .filter_map(|entry| match entry {
+            RouteEntry::Pool(members) => Some(members),
+            RouteEntry::Single(_) => None,
+        })
+        .flatten()
+        .map(|member| member.name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(routes: &str) -> Result<Router, RoutesConfigError> {
+        Router::new(toml::from_str(routes).unwrap())
+    }
+
+    #[test]
+    fn rejects_an_unknown_default() {
+        let routes = r#"
+            default = "missing"
+
+            [routes.home]
+            type = "direct"
+        "#;
+
+        assert!(router(routes).is_err());
+    }
+
+    #[test]
+    fn resolves_pool_members() {
+        let routes = r#"
+            default = "pool"
+
+            [routes.pool]
+            type = "pool"
+            members = ["first", "second"]
+
+            [routes.first]
+            type = "http"
+            url = "http://first.example:8080"
+
+            [routes.second]
+            type = "socks5"
+            url = "socks5://second.example:1080"
+        "#;
+
+        let router = router(routes).unwrap();
+
+        match router.routes.get("pool") {
+            Some(RouteEntry::Pool(members)) => assert_eq!(members.len(), 2),
+            _ => panic!("Expected a pool"),
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Upstream proxies and the templating of their credentials
+//!
+//! Credentials may reference session fields, e.g. a username of
+//! `user-{customer}-session-{session_id}` forwards the session id to the
+//! upstream so residential exits stay sticky. Fields a session lacks are left
+//! empty.
+
+use std::fmt;
+
+use reqwest_impersonate::Url;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::auth::Session;
+
+/// The placeholders available in credential templates
+pub const PLACEHOLDERS: [&str; 4] = ["customer", "session_id", "country", "session_time"];
+
+/// An upstream proxy with its credentials already filled in
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Upstream {
+    url: Url,
+}
+
+impl Upstream {
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// The url along with the password, which must never be logged. Tells
+    /// apart upstreams whose credentials changed
+    pub fn key(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// The url without the password, safe to log
+    pub fn id(&self) -> String {
+        let mut url = self.url.clone();
+        let _ = url.set_password(None);
+
+        url.to_string()
+    }
+}
+
+impl fmt::Debug for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Upstream").field(&self.id()).finish()
+    }
+}
+
+// Upstreams are persisted along with their credentials, see `storage::StateFile`
+impl Serialize for Upstream {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.url.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Upstream {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let url = String::deserialize(deserializer)?;
+
+        Url::parse(&url)
+            .map(|url| Self { url })
+            .map_err(de::Error::custom)
+    }
+}
+
+/// An upstream as defined in the routes file, rendered into an [`Upstream`]
+/// for every session
+#[derive(Clone)]
+pub struct UpstreamTemplate {
+    url: Url,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl UpstreamTemplate {
+    pub fn new(url: Url, username: Option<String>, password: Option<String>) -> Self {
+        Self {
+            url,
+            username,
+            password,
+        }
+    }
+
+    pub fn render(&self, session: &Session) -> Upstream {
+        self.render_with(|name| placeholder_value(name, session))
+    }
+
+    /// The upstream used by health checks. Probes have no session, so
+    /// templated credentials are replaced by the probe credentials, [`None`]
+    /// if those are missing
+    pub fn render_probe(&self, username: Option<&str>, password: Option<&str>) -> Option<Upstream> {
+        let mut url = self.url.clone();
+
+        if let Some(template) = &self.username {
+            let _ = url.set_username(probe_credential(template, username)?);
+        }
+
+        if let Some(template) = &self.password {
+            let _ = url.set_password(Some(probe_credential(template, password)?));
+        }
+
+        Some(Upstream { url })
+    }
+
+    fn render_with(&self, value: impl Fn(&str) -> String) -> Upstream {
+        let mut url = self.url.clone();
+
+        // Setting credentials only fails for urls without a host, which the
+        // routes validation already rules out
+        if let Some(username) = &self.username {
+            let _ = url.set_username(&render(username, &value));
+        }
+
+        if let Some(password) = &self.password {
+            let _ = url.set_password(Some(&render(password, &value)));
+        }
+
+        Upstream { url }
+    }
+}
+
+/// Get the names of every `{placeholder}` in the template
+pub fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+}
+
+fn render(template: &str, value: impl Fn(&str) -> String) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) => {
+                rendered.push_str(&value(&after[..end]));
+                rest = &after[end + 1..];
+            }
+            None => {
+                rendered.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+/// The template itself if it is a plain credential, the probe one otherwise
+fn probe_credential<'a>(template: &'a str, probe: Option<&'a str>) -> Option<&'a str> {
+    match placeholders(template).next() {
+        Some(_) => probe,
+        None => Some(template),
+    }
+}
+
+fn placeholder_value(name: &str, session: &Session) -> String {
+    match name {
+        "customer" => session.customer().to_string(),
+        "session_id" => session.session_id().unwrap_or_default().to_string(),
+        "country" => session.country().unwrap_or_default().to_string(),
+        "session_time" => session.session_time().as_secs().to_string(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(name: &str) -> String {
+        match name {
+            "customer" => "acme".to_string(),
+            "session_id" => "abc123".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    #[test]
+    fn render_fills_placeholders() {
+        assert_eq!(
+            render("user-{customer}-session-{session_id}", values),
+            "user-acme-session-abc123"
+        );
+        assert_eq!(render("{customer}{customer}", values), "acmeacme");
+    }
+
+    #[test]
+    fn render_leaves_unknown_placeholders_empty() {
+        assert_eq!(render("user-{country}-{nope}", values), "user--");
+    }
+
+    #[test]
+    fn render_keeps_plain_text() {
+        assert_eq!(render("plain", values), "plain");
+        assert_eq!(render("", values), "");
+        assert_eq!(render("user-{customer", values), "user-{customer");
+        assert_eq!(render("user-}customer{", values), "user-}customer{");
+    }
+
+    #[test]
+    fn placeholders_are_listed() {
+        let names: Vec<_> = placeholders("user-{customer}-{session_id}-{unclosed").collect();
+
+        assert_eq!(names, ["customer", "session_id"]);
+    }
+
+    #[test]
+    fn template_sets_the_credentials() {
+        let template = UpstreamTemplate::new(
+            Url::parse("http://proxy.example:8080").unwrap(),
+            Some("user-{customer}".to_string()),
+            Some("secret-{session_id}".to_string()),
+        );
+
+        let upstream = template.render_with(values);
+
+        assert_eq!(upstream.url().username(), "user-acme");
+        assert_eq!(upstream.url().password(), Some("secret-abc123"));
+        // The password never ends up in the id
+        assert_eq!(upstream.id(), "http://user-acme@proxy.example:8080/");
+    }
+
+    #[test]
+    fn probes_replace_templated_credentials() {
+        let url = Url::parse("http://proxy.example:8080").unwrap();
+        let templated = UpstreamTemplate::new(
+            url.clone(),
+            Some("user-{customer}".to_string()),
+            Some("secret".to_string()),
+        );
+        let plain = UpstreamTemplate::new(url, Some("user".to_string()), None);
+
+        assert!(templated.render_probe(None, None).is_none());
+
+        let upstream = templated.render_probe(Some("probe"), None).unwrap();
+        assert_eq!(upstream.url().username(), "probe");
+        assert_eq!(upstream.url().password(), Some("secret"));
+
+        let upstream = plain.render_probe(None, None).unwrap();
+        assert_eq!(upstream.url().username(), "user");
+        assert_eq!(upstream.url().password(), None);
+    }
+
+    #[test]
+    fn keys_tell_passwords_apart() {
+        let upstream = |password: &str| {
+            UpstreamTemplate::new(
+                Url::parse("http://proxy.example:8080").unwrap(),
+                Some("user".to_string()),
+                Some(password.to_string()),
+            )
+            .render_with(values)
+        };
+
+        assert_eq!(upstream("old").id(), upstream("new").id());
+        assert_ne!(upstream("old").key(), upstream("new").key());
+    }
+}
@@ -1,59 +1,204 @@
-use std::time::Duration;
+use std::sync::Arc;
 
-use log::trace;
-use reqwest_impersonate::{browser::ChromeVersion, Client};
+use error_stack::{IntoReport, Result, ResultExt};
+use log::{debug, trace, warn};
+use reqwest_impersonate::Client;
+use serde::{Deserialize, Serialize};
 use sha1::Digest;
+use thiserror::Error;
+
+use super::{persistence::Persisted, ConnectionHash, Storage, StorageMetrics, Sweep};
+use crate::{
+    auth::{DigestKey, Session},
+    browser::Browser,
+    route::RouteType,
+    timeouts::Timeouts,
+};
+
+#[derive(Debug, Error)]
+#[error("Could not build a client for the route")]
+pub struct BuildClientError;
+
+/// What a client was built with, enough to build an identical one after a
+/// restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientAffinity {
+    pub route: RouteType,
+    pub browser: Browser,
+    pub timeouts: Timeouts,
+}
 
-use super::{ConnectionHash, Storage};
-use crate::auth::Session;
+impl ClientAffinity {
+    fn build_client(&self) -> Result<Client, BuildClientError> {
+        self.route
+            .configure(self.browser.client_builder())
+            .and_then(|builder| self.timeouts.configure(builder).build())
+            .into_report()
+            .attach_printable_lazy(|| format!("Route: {}", self.route.id()))
+            .change_context(BuildClientError)
+    }
+}
+
+struct StoredClient {
+    client: Client,
+    affinity: ClientAffinity,
+}
 
-#[allow(dead_code)]
-#[derive(Clone)]
 pub struct ClientStorage {
-    inner: Storage<ClientHash, Client>,
+    inner: Storage<ClientHash, Arc<StoredClient>>,
+    /// Stands in for the passwords of sessions in the [`ClientHash`]
+    digest_key: DigestKey,
 }
 
 impl ClientStorage {
-    pub fn new() -> Self {
+    /// A storage keeping at most roughly `max_clients` clients alive
+    pub fn new(max_clients: usize, digest_key: DigestKey) -> Self {
         Self {
-            inner: Storage::new(),
+            inner: Storage::with_capacity(max_clients).with_eviction_listener(
+                |client_hash: &ClientHash, stored: Arc<StoredClient>| {
+                    debug!(
+                        "Shutting down the client {client_hash:?} of route {}",
+                        stored.affinity.route.id()
+                    );
+
+                    // Closes the idle connections of the pool, requests still in flight keep
+                    // their own handle
+                    drop(stored);
+                },
+            ),
+            digest_key,
         }
     }
 
-    /// Get a client based on the [`ConnectionHash`]
-    pub fn acquire_client(&mut self, client_hash: ClientHash, session: &Session) -> &mut Client {
+    pub fn digest_key(&self) -> &DigestKey {
+        &self.digest_key
+    }
+
+    /// Identifies the client of a session on a connection
+    pub fn client_hash(
+        &self,
+        conn_hash: &ConnectionHash,
+        session: &Session,
+        route: &RouteType,
+        timeouts: &Timeouts,
+    ) -> ClientHash {
+        ClientHash::new(
+            conn_hash,
+            session,
+            &session.password_digest(&self.digest_key),
+            route,
+            timeouts,
+        )
+    }
+
+    /// Get a client based on the [`ClientHash`]. Rotating sessions always get a
+    /// fresh client that is never stored
+    pub fn acquire_client(
+        &self,
+        client_hash: ClientHash,
+        session: &Session,
+        route: &RouteType,
+        timeouts: Timeouts,
+    ) -> Result<Client, BuildClientError> {
+        let affinity = ClientAffinity {
+            route: route.clone(),
+            browser: session.browser(),
+            timeouts,
+        };
+
+        if !session.is_sticky() {
+            return affinity.build_client();
+        }
+
         let f = || {
-            reqwest_impersonate::Client::builder()
-                .chrome_builder(ChromeVersion::V104)
-                .build()
-                .unwrap()
+            affinity.build_client().map(|client| {
+                Arc::new(StoredClient {
+                    client,
+                    affinity: affinity.clone(),
+                })
+            })
         };
 
-        let dur = Duration::from_secs(session.session_time());
+        // Clients are reference counted internally, so handing out clones is cheap.
+        // If the session_time has changed, the expiration time gets updated
+        self.inner
+            .try_get_or_set_with_duration(client_hash, f, session.session_time())
+            .map(|stored| stored.client.clone())
+    }
 
-        let expiring = self.inner.get_or_set_with_duration(client_hash, f, dur);
+    /// The affinity of every stored client along with its expiration
+    pub fn snapshot(&self) -> Vec<Persisted<ClientHash, ClientAffinity>> {
+        self.inner
+            .entries()
+            .into_iter()
+            .map(|(client_hash, stored, duration, remaining)| Persisted {
+                key: client_hash,
+                value: stored.affinity.clone(),
+                duration,
+                remaining,
+            })
+            .collect()
+    }
 
-        // If the session_time has changed, update the expiration time
-        if expiring.duration != dur {
-            expiring.set_duration(dur)
+    /// Rebuilds the clients of a snapshot, returning how many could be built
+    pub fn restore(&self, entries: Vec<Persisted<ClientHash, ClientAffinity>>) -> usize {
+        let mut restored = 0;
+
+        for entry in entries {
+            match entry.value.build_client() {
+                Ok(client) => {
+                    let stored = StoredClient {
+                        client,
+                        affinity: entry.value,
+                    };
+
+                    self.inner.restore(
+                        entry.key,
+                        Arc::new(stored),
+                        entry.duration,
+                        entry.remaining,
+                    );
+                    restored += 1;
+                }
+                Err(err) => warn!("Could not restore a client\n{err:?}"),
+            }
         }
 
-        expiring.get_mut()
+        restored
+    }
+}
+
+impl Sweep for ClientStorage {
+    fn sweep(&self) -> usize {
+        self.inner.sweep()
+    }
+
+    fn metrics(&self) -> StorageMetrics {
+        self.inner.metrics()
     }
 }
 
 /// Represents an unique identifier to get a client with
-#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 pub struct ClientHash(String);
 
 impl ClientHash {
-    pub fn new(conn_hash: &ConnectionHash, session: &Session, route_type: &str) -> Self {
+    fn new(
+        conn_hash: &ConnectionHash,
+        session: &Session,
+        password_digest: &str,
+        route: &RouteType,
+        timeouts: &Timeouts,
+    ) -> Self {
         let mut hasher = sha1::Sha1::new();
 
         hasher.update(conn_hash);
-        hasher.update(session.session_id());
-        hasher.update(session.password());
-        hasher.update(route_type);
+        hasher.update(session.session_id().unwrap_or_default());
+        hasher.update(password_digest);
+        // Clients built with outdated upstream credentials aren't reused
+        hasher.update(route.key());
+        hasher.update(session.browser().name());
+        hasher.update(timeouts.id());
 
         let finished = hasher.finalize();
 
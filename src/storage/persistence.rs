@@ -0,0 +1,230 @@
+//! Optional on-disk snapshot of the sessions and the clients they are tied
+//! to, so that sticky sessions keep their exit across restarts. The snapshot
+//! is loaded on start, then written periodically and once more on shutdown.
+//!
+//! Passwords are never written, sessions only keep a digest keyed with the
+//! key file next to the snapshot. Restored sessions have to authenticate
+//! again, the digest only leads them back to their clients
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use error_stack::{bail, IntoReport, Report, Result, ResultExt};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+use super::{
+    client_storage::ClientAffinity, ClientHash, ClientStorage, ConnectionHash, SessionStorage,
+};
+use crate::auth::{DigestKey, StoredSession, DIGEST_KEY_LEN};
+
+#[derive(Debug, Error)]
+#[error("Could not persist the proxy state")]
+pub struct PersistError;
+
+/// A stored value along with its expiration
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Persisted<K, V> {
+    pub key: K,
+    pub value: V,
+    pub duration: Duration,
+    pub remaining: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    saved_at: SystemTime,
+    sessions: Vec<Persisted<ConnectionHash, StoredSession>>,
+    clients: Vec<Persisted<ClientHash, ClientAffinity>>,
+}
+
+pub struct StateFile {
+    path: PathBuf,
+    snapshot_interval: Duration,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf, snapshot_interval: Duration) -> Self {
+        Self {
+            path,
+            snapshot_interval,
+        }
+    }
+
+    /// The key of the password digests, created along with the first
+    /// snapshot. Without it the digests of a snapshot can't be matched
+    pub fn load_key(&self) -> Result<DigestKey, PersistError> {
+        let path = self.key_path();
+
+        if !path.exists() {
+            let key = DigestKey::generate();
+            write_private(&path, hex::encode(key.expose()).as_bytes())?;
+
+            return Ok(key);
+        }
+
+        let contents = fs::read_to_string(&path)
+            .into_report()
+            .attach_printable_lazy(|| format!("Path: {}", path.display()))
+            .change_context(PersistError)?;
+
+        let key = hex::decode(contents.trim())
+            .ok()
+            .and_then(|key| <[u8; DIGEST_KEY_LEN]>::try_from(key).ok());
+
+        match key {
+            Some(key) => Ok(DigestKey::from_bytes(key)),
+            None => bail!(Report::new(PersistError).attach_printable(format!(
+                "{} does not hold a {DIGEST_KEY_LEN} byte hex encoded key",
+                path.display()
+            ))),
+        }
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.path.with_extension("key")
+    }
+
+    /// Restores the snapshot, if one was written before. Entries keep the
+    /// time they had left, minus the time the proxy was down
+    pub fn load(
+        &self,
+        sessions: &SessionStorage,
+        clients: &ClientStorage,
+    ) -> Result<(), PersistError> {
+        if !self.path.exists() {
+            info!("No state found at {}, starting fresh", self.path.display());
+
+            return Ok(());
+        }
+
+        let contents = fs::read_to_string(&self.path)
+            .into_report()
+            .attach_printable_lazy(|| format!("Path: {}", self.path.display()))
+            .change_context(PersistError)?;
+
+        let snapshot: Snapshot = serde_json::from_str(&contents)
+            .into_report()
+            .attach_printable_lazy(|| format!("Path: {}", self.path.display()))
+            .change_context(PersistError)?;
+
+        let downtime = snapshot.saved_at.elapsed().unwrap_or_default();
+
+        let restored_sessions = sessions.restore(still_alive(snapshot.sessions, downtime));
+        let restored_clients = clients.restore(still_alive(snapshot.clients, downtime));
+
+        info!(
+            "Restored {restored_sessions} sessions and {restored_clients} clients from {}",
+            self.path.display()
+        );
+
+        Ok(())
+    }
+
+    /// Writes the snapshot to a temporary file first, so a crash mid-write
+    /// never leaves a truncated state behind
+    pub fn save(
+        &self,
+        sessions: &SessionStorage,
+        clients: &ClientStorage,
+    ) -> Result<(), PersistError> {
+        let snapshot = Snapshot {
+            saved_at: SystemTime::now(),
+            sessions: sessions.snapshot(clients.digest_key()),
+            clients: clients.snapshot(),
+        };
+
+        let contents = serde_json::to_vec(&snapshot)
+            .into_report()
+            .change_context(PersistError)?;
+
+        let tmp_path = self.path.with_extension("tmp");
+
+        write_private(&tmp_path, &contents)?;
+
+        fs::rename(&tmp_path, &self.path)
+            .into_report()
+            .attach_printable_lazy(|| format!("Path: {}", self.path.display()))
+            .change_context(PersistError)?;
+
+        debug!(
+            "Saved {} sessions and {} clients to {}",
+            snapshot.sessions.len(),
+            snapshot.clients.len(),
+            self.path.display()
+        );
+
+        Ok(())
+    }
+
+    /// Saves the state every snapshot interval, so that a crash only loses
+    /// what changed since the last one
+    pub fn spawn_snapshots(
+        self: &Arc<Self>,
+        sessions: &Arc<SessionStorage>,
+        clients: &Arc<ClientStorage>,
+    ) -> JoinHandle<()> {
+        let (state_file, sessions, clients) = (self.clone(), sessions.clone(), clients.clone());
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(state_file.snapshot_interval);
+
+            // The first tick completes right away, there is nothing new to save yet
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                let save = {
+                    let (state_file, sessions, clients) =
+                        (state_file.clone(), sessions.clone(), clients.clone());
+
+                    move || state_file.save(&sessions, &clients)
+                };
+
+                let saved = tokio::task::spawn_blocking(save).await;
+
+                match saved {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => error!("Could not save a snapshot\n{err:?}"),
+                    Err(err) => error!("The snapshot task failed: {err}"),
+                }
+            }
+        })
+    }
+}
+
+/// Writes a file only the current user can read, upstream urls contain the
+/// credentials of the providers
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), PersistError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents).and_then(|_| file.sync_all()))
+        .into_report()
+        .attach_printable_lazy(|| format!("Path: {}", path.display()))
+        .change_context(PersistError)
+}
+
+fn still_alive<K, V>(entries: Vec<Persisted<K, V>>, downtime: Duration) -> Vec<Persisted<K, V>> {
+    entries
+        .into_iter()
+        .filter_map(|mut entry| {
+            entry.remaining = entry.remaining.checked_sub(downtime)?;
+
+            (!entry.remaining.is_zero()).then_some(entry)
+        })
+        .collect()
+}
@@ -1,34 +1,76 @@
-use std::time::Duration;
+use log::trace;
 
-use super::{ConnectionHash, Storage};
-use crate::auth::Session;
+use super::{persistence::Persisted, ConnectionHash, Storage, StorageMetrics, Sweep};
+use crate::auth::{DigestKey, Session, StoredSession};
 
-#[allow(dead_code)]
-#[derive(Clone)]
 pub struct SessionStorage {
     inner: Storage<ConnectionHash, Session>,
 }
 
 impl SessionStorage {
-    pub fn new() -> Self {
+    /// A storage keeping at most roughly `max_sessions` sessions alive
+    pub fn new(max_sessions: usize) -> Self {
         Self {
-            inner: Storage::new(),
+            inner: Storage::with_capacity(max_sessions).with_eviction_listener(
+                |conn_hash: &ConnectionHash, session: Session| {
+                    trace!(
+                        "Session of \"{}\" for {conn_hash:?} expired",
+                        session.customer()
+                    );
+                },
+            ),
         }
     }
 
     /// Insert a new [`Session`] and get the old one if it exists for the given
     /// [`ConnectionHash`]
-    pub fn insert_session(
-        &mut self,
-        conn_hash: ConnectionHash,
-        session: Session,
-    ) -> Option<Session> {
-        let dur = Duration::from_secs(session.session_time());
+    pub fn insert_session(&self, conn_hash: ConnectionHash, session: Session) -> Option<Session> {
+        let dur = session.session_time();
         self.inner.set_with_duration(conn_hash, session, dur)
     }
 
     /// Get a [`Session`] for the given [`ConnectionHash`]
-    pub fn get_session(&mut self, conn_hash: &ConnectionHash) -> Option<&Session> {
+    pub fn get_session(&self, conn_hash: &ConnectionHash) -> Option<Session> {
         self.inner.get(conn_hash)
     }
+
+    /// Every live [`Session`] along with its expiration, with the passwords
+    /// replaced by their digest
+    pub fn snapshot(&self, key: &DigestKey) -> Vec<Persisted<ConnectionHash, StoredSession>> {
+        self.inner
+            .entries()
+            .into_iter()
+            .map(|(conn_hash, session, duration, remaining)| Persisted {
+                key: conn_hash,
+                value: session.to_stored(key),
+                duration,
+                remaining,
+            })
+            .collect()
+    }
+
+    pub fn restore(&self, entries: Vec<Persisted<ConnectionHash, StoredSession>>) -> usize {
+        let restored = entries.len();
+
+        for entry in entries {
+            self.inner.restore(
+                entry.key,
+                Session::from_stored(entry.value),
+                entry.duration,
+                entry.remaining,
+            );
+        }
+
+        restored
+    }
+}
+
+impl Sweep for SessionStorage {
+    fn sweep(&self) -> usize {
+        self.inner.sweep()
+    }
+
+    fn metrics(&self) -> StorageMetrics {
+        self.inner.metrics()
+    }
 }
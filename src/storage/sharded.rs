@@ -0,0 +1,509 @@
+//! A concurrent map whose entries expire after a per-entry duration. Keys are
+//! spread over independently locked shards, so lookups only ever wait on a
+//! writer of the same shard and never on each other.
+//!
+//! Expired entries are invisible right away, but they are only dropped once
+//! [`Storage::sweep`] runs or their slot is needed again
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash, Hasher},
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Shards created for every available core
+const SHARDS_PER_CORE: usize = 4;
+
+type EvictionListener<K, V> = Box<dyn Fn(&K, V) + Send + Sync>;
+
+pub struct Storage<K, V> {
+    shards: Box<[RwLock<HashMap<K, ExpiringValue<V>>>]>,
+    hasher: RandomState,
+    /// Entries a single shard may hold, unbounded if [`None`]
+    shard_capacity: Option<usize>,
+    on_evict: Option<EvictionListener<K, V>>,
+    evicted: AtomicU64,
+}
+
+/// A snapshot of the counters of a [`Storage`]
+#[derive(Debug, Clone, Copy)]
+pub struct StorageMetrics {
+    /// Entries currently held, including expired ones that were not swept yet
+    pub entries: usize,
+    /// Entries evicted since the storage was created
+    pub evicted: u64,
+}
+
+struct ExpiringValue<T> {
+    inner: T,
+    created_at: Instant,
+    duration: Duration,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Storage<K, V> {
+    /// An unbounded storage with a shard count based on the available cores
+    pub fn new() -> Self {
+        Self::with_shards(default_shard_count(), None)
+    }
+
+    /// A storage holding roughly `capacity` entries at most. Once a shard is
+    /// full, the entry closest to expiring makes room for the new one
+    pub fn with_capacity(capacity: usize) -> Self {
+        let shards = default_shard_count();
+
+        Self::with_shards(shards, Some((capacity / shards).max(1)))
+    }
+
+    /// The shard count is rounded up to the next power of two
+    pub fn with_shards(shards: usize, shard_capacity: Option<usize>) -> Self {
+        let shards = (0..shards.max(1).next_power_of_two())
+            .map(|_| RwLock::new(HashMap::new()))
+            .collect();
+
+        Self {
+            shards,
+            hasher: RandomState::new(),
+            shard_capacity,
+            on_evict: None,
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    /// Calls `f` with every entry that expired or had to make room for
+    /// another one. It never runs while a shard is locked, so it may take its
+    /// time to tear the value down
+    pub fn with_eviction_listener<F: Fn(&K, V) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_evict = Some(Box::new(f));
+        self
+    }
+
+    pub fn get(&self, k: &K) -> Option<V> {
+        self.read_shard(k)
+            .get(k)
+            .filter(|v| !v.is_expired())
+            .map(|v| v.inner.clone())
+    }
+
+    pub fn set_with_duration(&self, k: K, v: V, d: Duration) -> Option<V> {
+        self.insert(k, ExpiringValue::new(v, d))
+    }
+
+    /// Inserts a value that already used up part of its duration, e.g. one
+    /// loaded from disk
+    pub fn restore(&self, k: K, v: V, d: Duration, remaining: Duration) -> Option<V> {
+        let elapsed = d.saturating_sub(remaining);
+
+        let value = match Instant::now().checked_sub(elapsed) {
+            Some(created_at) => ExpiringValue {
+                inner: v,
+                created_at,
+                duration: d,
+            },
+            // The clock can't go back that far, settle for the time left
+            None => ExpiringValue::new(v, remaining),
+        };
+
+        self.insert(k, value)
+    }
+
+    /// Clones every live value, along with its duration and the time it has
+    /// left
+    pub fn entries(&self) -> Vec<(K, V, Duration, Duration)> {
+        let now = Instant::now();
+
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .iter()
+                    .filter(|(_, v)| !v.is_expired())
+                    .map(|(k, v)| {
+                        let remaining = v.expires_at().saturating_duration_since(now);
+
+                        (k.clone(), v.inner.clone(), v.duration, remaining)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn remove(&self, k: &K) -> Option<V> {
+        let mut evicted = Vec::new();
+
+        let old = self.write_shard(k).remove(k);
+
+        let old = live_or_evicted(k.clone(), old, &mut evicted);
+        self.evict(evicted);
+
+        old
+    }
+
+    /// Replaces the value of `k` with the one built from its current value.
+    /// The shard stays locked in between, so concurrent updates are not lost
+    pub fn update_with<F: FnOnce(Option<V>) -> (V, Duration)>(&self, k: K, f: F) {
+        let mut evicted = Vec::new();
+
+        {
+            let mut shard = self.write_shard(&k);
+
+            let current = live_or_evicted(k.clone(), shard.remove(&k), &mut evicted);
+
+            if current.is_none() {
+                make_room(&mut shard, self.shard_capacity, &mut evicted);
+            }
+
+            let (v, d) = f(current);
+            shard.insert(k, ExpiringValue::new(v, d));
+        }
+
+        self.evict(evicted);
+    }
+
+    /// Get the value for `k`, or create it with `f` if there is none. If `d`
+    /// differs from the duration of an existing value, its expiration time is
+    /// reset with the new duration.
+    ///
+    /// `f` runs without holding any lock, so a slow build doesn't hold up the
+    /// other keys of the shard. If another caller stored a value for `k`
+    /// meanwhile, that one wins and the built value is dropped
+    pub fn try_get_or_set_with_duration<E, F: FnOnce() -> Result<V, E>>(
+        &self,
+        k: K,
+        f: F,
+        d: Duration,
+    ) -> Result<V, E> {
+        // Most calls find an up to date value, which only needs a read lock
+        if let Some(v) = self.read_shard(&k).get(&k) {
+            if !v.is_expired() && v.duration == d {
+                return Ok(v.inner.clone());
+            }
+        }
+
+        if let Some(v) = self.refresh(&k, d) {
+            return Ok(v);
+        }
+
+        let built = f()?;
+        let mut evicted = Vec::new();
+
+        let value = {
+            let mut shard = self.write_shard(&k);
+
+            match shard.get_mut(&k).filter(|v| !v.is_expired()) {
+                Some(v) => {
+                    if v.duration != d {
+                        v.set_duration(d);
+                    }
+
+                    v.inner.clone()
+                }
+                None => {
+                    if let Some(expired) = shard.remove(&k) {
+                        evicted.push((k.clone(), expired.inner));
+                    } else {
+                        make_room(&mut shard, self.shard_capacity, &mut evicted);
+                    }
+
+                    shard.insert(k, ExpiringValue::new(built.clone(), d));
+
+                    built
+                }
+            }
+        };
+
+        self.evict(evicted);
+
+        Ok(value)
+    }
+
+    /// The live value of `k`, with its expiration time reset if `d` differs
+    /// from its duration
+    fn refresh(&self, k: &K, d: Duration) -> Option<V> {
+        let mut shard = self.write_shard(k);
+        let v = shard.get_mut(k).filter(|v| !v.is_expired())?;
+
+        if v.duration != d {
+            v.set_duration(d);
+        }
+
+        Some(v.inner.clone())
+    }
+
+    /// Drops every expired entry, returning how many there were. Shards are
+    /// only write locked if they hold expired entries
+    pub fn sweep(&self) -> usize {
+        let mut swept = 0;
+
+        for shard in self.shards.iter() {
+            let expired: Vec<K> = shard
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .filter(|(_, v)| v.is_expired())
+                .map(|(k, _)| k.clone())
+                .collect();
+
+            if expired.is_empty() {
+                continue;
+            }
+
+            let evicted: Vec<(K, V)> = {
+                let mut shard = shard.write().unwrap_or_else(PoisonError::into_inner);
+
+                expired
+                    .into_iter()
+                    .filter_map(|k| {
+                        // The entry might have been refreshed in between
+                        match shard.get(&k) {
+                            Some(v) if v.is_expired() => {
+                                shard.remove(&k).map(|expired| (k, expired.inner))
+                            }
+                            _ => None,
+                        }
+                    })
+                    .collect()
+            };
+
+            swept += evicted.len();
+            self.evict(evicted);
+        }
+
+        swept
+    }
+
+    pub fn metrics(&self) -> StorageMetrics {
+        StorageMetrics {
+            entries: self
+                .shards
+                .iter()
+                .map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner).len())
+                .sum(),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+
+    fn insert(&self, k: K, value: ExpiringValue<V>) -> Option<V> {
+        let mut evicted = Vec::new();
+
+        let old = {
+            let mut shard = self.write_shard(&k);
+
+            if !shard.contains_key(&k) {
+                make_room(&mut shard, self.shard_capacity, &mut evicted);
+            }
+
+            shard.insert(k.clone(), value)
+        };
+
+        let old = live_or_evicted(k, old, &mut evicted);
+        self.evict(evicted);
+
+        old
+    }
+
+    /// Counts the entries that expired or had to make room for another one
+    /// and hands them to the eviction listener. This runs outside of the shard
+    /// lock, so tearing them down never blocks other lookups
+    fn evict(&self, evicted: Vec<(K, V)>) {
+        if evicted.is_empty() {
+            return;
+        }
+
+        self.evicted
+            .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+
+        if let Some(on_evict) = &self.on_evict {
+            for (k, v) in evicted {
+                on_evict(&k, v);
+            }
+        }
+    }
+
+    fn shard_index(&self, k: &K) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        k.hash(&mut hasher);
+
+        // The shard count is always a power of two
+        hasher.finish() as usize & (self.shards.len() - 1)
+    }
+
+    // A panic while holding the lock can't leave an entry half written, so
+    // poisoning is ignored
+    fn read_shard(&self, k: &K) -> RwLockReadGuard<'_, HashMap<K, ExpiringValue<V>>> {
+        self.shards[self.shard_index(k)]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_shard(&self, k: &K) -> RwLockWriteGuard<'_, HashMap<K, ExpiringValue<V>>> {
+        self.shards[self.shard_index(k)]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Makes sure another entry fits in the shard, expired entries go first
+fn make_room<K: Hash + Eq + Clone, V>(
+    shard: &mut HashMap<K, ExpiringValue<V>>,
+    capacity: Option<usize>,
+    evicted: &mut Vec<(K, V)>,
+) {
+    let capacity = match capacity {
+        Some(capacity) if shard.len() >= capacity => capacity,
+        _ => return,
+    };
+
+    let mut victims: Vec<K> = shard
+        .iter()
+        .filter(|(_, v)| v.is_expired())
+        .map(|(k, _)| k.clone())
+        .collect();
+
+    if victims.is_empty() {
+        victims.extend(
+            shard
+                .iter()
+                .min_by_key(|(_, v)| v.expires_at())
+                .map(|(k, _)| k.clone()),
+        );
+    }
+
+    for k in victims {
+        if let Some(v) = shard.remove(&k) {
+            evicted.push((k, v.inner));
+        }
+
+        if shard.len() < capacity {
+            break;
+        }
+    }
+}
+
+/// Sorts a value that was taken out of the storage, expired values count as
+/// evicted
+fn live_or_evicted<K, V>(
+    k: K,
+    value: Option<ExpiringValue<V>>,
+    evicted: &mut Vec<(K, V)>,
+) -> Option<V> {
+    match value {
+        Some(v) if v.is_expired() => {
+            evicted.push((k, v.inner));
+            None
+        }
+        other => other.map(|v| v.inner),
+    }
+}
+
+impl<T> ExpiringValue<T> {
+    fn new(inner: T, duration: Duration) -> Self {
+        Self {
+            inner,
+            created_at: Instant::now(),
+            duration,
+        }
+    }
+
+    fn expires_at(&self) -> Instant {
+        self.created_at + self.duration
+    }
+
+    fn is_expired(&self) -> bool {
+        Instant::now() > self.expires_at()
+    }
+
+    /// Resets the expiration time of the [`ExpiringValue`] with the new duration
+    fn set_duration(&mut self, d: Duration) {
+        self.created_at = Instant::now();
+        self.duration = d;
+    }
+}
+
+fn default_shard_count() -> usize {
+    let cores = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+    (cores * SHARDS_PER_CORE).next_power_of_two()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn listened(capacity: usize) -> (Storage<u32, u32>, Arc<Mutex<Vec<(u32, u32)>>>) {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let listener = evicted.clone();
+
+        let storage = Storage::with_shards(1, Some(capacity))
+            .with_eviction_listener(move |k: &u32, v| listener.lock().unwrap().push((*k, v)));
+
+        (storage, evicted)
+    }
+
+    #[test]
+    fn full_shards_evict_the_entry_closest_to_expiring() {
+        let (storage, evicted) = listened(2);
+
+        storage.set_with_duration(1, 10, Duration::from_secs(10));
+        storage.set_with_duration(2, 20, Duration::from_secs(60));
+        storage.set_with_duration(3, 30, Duration::from_secs(60));
+
+        assert_eq!(*evicted.lock().unwrap(), [(1, 10)]);
+        assert_eq!(storage.get(&1), None);
+        assert_eq!(storage.metrics().entries, 2);
+        assert_eq!(storage.metrics().evicted, 1);
+    }
+
+    #[test]
+    fn sweeps_evict_expired_entries() {
+        let (storage, evicted) = listened(10);
+
+        storage.set_with_duration(1, 10, Duration::ZERO);
+        storage.set_with_duration(2, 20, Duration::from_secs(60));
+        thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(storage.sweep(), 1);
+        assert_eq!(*evicted.lock().unwrap(), [(1, 10)]);
+        assert_eq!(storage.get(&2), Some(20));
+        assert_eq!(storage.metrics().evicted, 1);
+    }
+
+    #[test]
+    fn live_removals_are_not_evictions() {
+        let (storage, evicted) = listened(10);
+
+        storage.set_with_duration(1, 10, Duration::from_secs(60));
+
+        assert_eq!(storage.remove(&1), Some(10));
+        assert!(evicted.lock().unwrap().is_empty());
+        assert_eq!(storage.metrics().evicted, 0);
+    }
+
+    #[test]
+    fn values_are_built_outside_the_lock() {
+        let storage: Storage<u32, u32> = Storage::with_shards(1, None);
+
+        // Holding the lock would deadlock the lookup inside the build
+        let built = storage.try_get_or_set_with_duration(
+            1,
+            || Ok::<_, ()>(storage.get(&2).unwrap_or(10)),
+            Duration::from_secs(60),
+        );
+
+        assert_eq!(built, Ok(10));
+        assert_eq!(storage.get(&1), Some(10));
+        assert_eq!(
+            storage.try_get_or_set_with_duration(1, || Err(()), Duration::from_secs(60)),
+            Ok(10)
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Periodically drops expired entries, so that things like the connection
+//! pools of expired clients don't outlive their session
+
+use std::{
+    hash::Hash,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use log::{debug, info};
+use tokio::task::JoinHandle;
+
+use super::{sharded::StorageMetrics, Storage};
+
+/// Anything holding entries that expire
+pub trait Sweep: Send + Sync {
+    /// Drops the expired entries, returning how many there were
+    fn sweep(&self) -> usize;
+
+    fn metrics(&self) -> StorageMetrics;
+}
+
+impl<K, V> Sweep for Storage<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+{
+    fn sweep(&self) -> usize {
+        Storage::sweep(self)
+    }
+
+    fn metrics(&self) -> StorageMetrics {
+        Storage::metrics(self)
+    }
+}
+
+/// Sweeps `target` every `interval` until it is dropped, reporting its
+/// metrics whenever entries were evicted since the last report
+pub fn spawn_sweeper<T: Sweep + 'static>(
+    name: &'static str,
+    target: &Arc<T>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    let target: Weak<T> = Arc::downgrade(target);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut reported = 0;
+
+        loop {
+            ticker.tick().await;
+
+            let target = match target.upgrade() {
+                Some(target) => target,
+                None => break,
+            };
+
+            let swept = target.sweep();
+            let metrics = target.metrics();
+
+            if swept > 0 {
+                debug!("Swept {swept} expired entries from the {name} storage");
+            }
+
+            // Entries making room for others are evicted between sweeps
+            if metrics.evicted != reported {
+                info!(
+                    "The {name} storage holds {} entries, {} evicted since the last report and {} in total",
+                    metrics.entries,
+                    metrics.evicted - reported,
+                    metrics.evicted
+                );
+
+                reported = metrics.evicted;
+            }
+        }
+    })
+}
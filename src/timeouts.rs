@@ -0,0 +1,195 @@
+//! Time limits for requests to the host. The server defaults can be
+//! overridden per route, then per customer and finally per session
+
+use std::time::Duration;
+
+use error_stack::Result;
+use reqwest_impersonate::ClientBuilder;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{vars, ConfigError};
+
+const CONNECT_TIMEOUT: &str = "HUD_CONNECT_TIMEOUT";
+const FIRST_BYTE_TIMEOUT: &str = "HUD_FIRST_BYTE_TIMEOUT";
+const TOTAL_TIMEOUT: &str = "HUD_TOTAL_TIMEOUT";
+
+/// The timeouts a request ends up with once every level is merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Timeouts {
+    /// Opening the connection, including the upstream proxy if any and the
+    /// TLS handshake, which reqwest can't time on its own
+    pub connect: Duration,
+    /// Until the response head arrives, counted from when the request body
+    /// was sent
+    pub first_byte: Duration,
+    /// The whole exchange, including streaming the response body
+    pub total: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(20),
+            first_byte: Duration::from_secs(30),
+            total: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+/// Timeouts as written in the configuration, the ones left out fall back to
+/// the previous level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutOverrides {
+    #[serde(default, with = "timeout_str", skip_serializing_if = "Option::is_none")]
+    pub connect: Option<Duration>,
+    #[serde(default, with = "timeout_str", skip_serializing_if = "Option::is_none")]
+    pub first_byte: Option<Duration>,
+    #[serde(default, with = "timeout_str", skip_serializing_if = "Option::is_none")]
+    pub total: Option<Duration>,
+}
+
+impl TimeoutOverrides {
+    /// Overrides the server wide timeouts with `HUD_CONNECT_TIMEOUT`,
+    /// `HUD_FIRST_BYTE_TIMEOUT` and `HUD_TOTAL_TIMEOUT` (e.g. `500ms` or `2m`)
+    pub fn with_env(self) -> Result<Self, ConfigError> {
+        Ok(Self {
+            connect: vars::duration(CONNECT_TIMEOUT)?.or(self.connect),
+            first_byte: vars::duration(FIRST_BYTE_TIMEOUT)?.or(self.first_byte),
+            total: vars::duration(TOTAL_TIMEOUT)?.or(self.total),
+        })
+    }
+}
+
+impl Timeouts {
+    /// Applies the overrides that are set
+    pub fn with(self, overrides: &TimeoutOverrides) -> Self {
+        Self {
+            connect: overrides.connect.unwrap_or(self.connect),
+            first_byte: overrides.first_byte.unwrap_or(self.first_byte),
+            total: overrides.total.unwrap_or(self.total),
+        }
+    }
+
+    /// Makes a client builder enforce the connect and total timeouts, the
+    /// first byte one is enforced once the request was sent
+    pub fn configure(&self, builder: ClientBuilder) -> ClientBuilder {
+        builder.connect_timeout(self.connect).timeout(self.total)
+    }
+
+    /// Unique identifier of the timeouts, used to keep clients with
+    /// different timeouts apart
+    pub fn id(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.connect.as_millis(),
+            self.first_byte.as_millis(),
+            self.total.as_millis()
+        )
+    }
+}
+
+/// Timeouts are written like the durations of the username parameters
+mod timeout_str {
+    use std::time::Duration;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    use crate::auth::parse_duration;
+
+    pub fn serialize<S: Serializer>(
+        timeout: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match timeout {
+            Some(timeout) => serializer.serialize_str(&format!("{}ms", timeout.as_millis())),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+
+        parse_duration(&raw)
+            .filter(|timeout| !timeout.is_zero())
+            .map(Some)
+            .ok_or_else(|| de::Error::custom(format!("\"{raw}\" is not a valid timeout")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Instant};
+
+    use reqwest_impersonate::Client;
+
+    use super::*;
+
+    #[test]
+    fn overrides_apply_level_by_level() {
+        let route = TimeoutOverrides {
+            connect: Some(Duration::from_secs(5)),
+            total: Some(Duration::from_secs(60)),
+            ..TimeoutOverrides::default()
+        };
+        let session = TimeoutOverrides {
+            total: Some(Duration::from_secs(10)),
+            ..TimeoutOverrides::default()
+        };
+
+        let timeouts = Timeouts::default().with(&route).with(&session);
+
+        assert_eq!(timeouts.connect, Duration::from_secs(5));
+        assert_eq!(timeouts.first_byte, Timeouts::default().first_byte);
+        assert_eq!(timeouts.total, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn empty_overrides_keep_the_timeouts() {
+        let timeouts = Timeouts::default().with(&TimeoutOverrides::default());
+
+        assert_eq!(timeouts, Timeouts::default());
+    }
+
+    #[test]
+    fn ids_tell_timeouts_apart() {
+        let shorter = Timeouts::default().with(&TimeoutOverrides {
+            first_byte: Some(Duration::from_secs(1)),
+            ..TimeoutOverrides::default()
+        });
+
+        assert_ne!(shorter.id(), Timeouts::default().id());
+        assert_eq!(Timeouts::default().id(), Timeouts::default().id());
+    }
+
+    #[test]
+    fn overrides_reject_zero() {
+        assert!(toml::from_str::<TimeoutOverrides>("total = \"0s\"").is_err());
+        assert!(toml::from_str::<TimeoutOverrides>("tls_handshake = \"5s\"").is_err());
+
+        let overrides: TimeoutOverrides = toml::from_str("first_byte = \"500ms\"").unwrap();
+        assert_eq!(overrides.first_byte, Some(Duration::from_millis(500)));
+        assert_eq!(overrides.total, None);
+    }
+
+    #[tokio::test]
+    async fn configure_enforces_the_total_timeout() {
+        // Connections are queued by the OS but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let timeouts = Timeouts::default().with(&TimeoutOverrides {
+            total: Some(Duration::from_millis(200)),
+            ..TimeoutOverrides::default()
+        });
+        let client = timeouts.configure(Client::builder()).build().unwrap();
+
+        let started = Instant::now();
+        let err = client.get(url).send().await.unwrap_err();
+
+        assert!(err.is_timeout());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}